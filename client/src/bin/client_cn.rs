@@ -63,6 +63,7 @@ async fn main() {
     } = CliOptions::parse();
 
     let (events_tx, events_rx) = broadcast::channel(1024);
+    let early_data = server.early_data;
 
     let outbound = Arc::new(cn::cn_outbound(
         dns_server
@@ -92,7 +93,8 @@ async fn main() {
                 .context("Error binding HTTP proxy listen address")?;
 
             tracing::info!("HTTP proxy listening on {}", listener.local_addr()?);
-            serve_listener::<HttpProxyHandshaker<_>, _>(listener, outbound.clone()).await
+            serve_listener::<HttpProxyHandshaker<_>, _>(listener, outbound.clone(), early_data)
+                .await
         }
         .instrument(info_span!("http_proxy"));
 
@@ -106,7 +108,8 @@ async fn main() {
                 .context("Error binding SOCKS5 proxy listen address")?;
 
             tracing::info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
-            serve_listener::<SocksProxyHandshaker<_>, _>(listener, outbound.clone()).await
+            serve_listener::<SocksProxyHandshaker<_>, _>(listener, outbound.clone(), early_data)
+                .await
         }
         .instrument(info_span!("socks5_proxy"));
 
@@ -37,7 +37,8 @@ async fn main() {
         socks5_proxy_listen,
     } = CliOptions::parse();
 
-    let outbound = Arc::new(ProtocolOutbound(config));
+    let early_data = config.early_data;
+    let outbound = Arc::new(ProtocolOutbound::new(config));
 
     let run_http_proxy = async {
         let Some(listen) = http_proxy_listen else {
@@ -50,7 +51,7 @@ async fn main() {
 
         tracing::info!("HTTP proxy listening on {}", listener.local_addr()?);
 
-        serve_listener::<HttpProxyHandshaker<_>, _>(listener, outbound.clone()).await
+        serve_listener::<HttpProxyHandshaker<_>, _>(listener, outbound.clone(), early_data).await
     };
 
     let run_socks5_proxy = async {
@@ -63,7 +64,7 @@ async fn main() {
             .context("Error binding SOCKS5 proxy listen address")?;
 
         tracing::info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
-        serve_listener::<SocksProxyHandshaker<_>, _>(listener, outbound.clone()).await
+        serve_listener::<SocksProxyHandshaker<_>, _>(listener, outbound.clone(), early_data).await
     };
 
     try_join!(run_http_proxy, run_socks5_proxy).unwrap();
@@ -87,6 +87,7 @@ pub unsafe extern "C" fn create_client(
             .context("Failed to create tokio TcpListener for api")?;
 
         let (events_tx, events) = broadcast::channel(100);
+        let early_data = main_server_config.early_data;
 
         let outbound = Arc::new(cn_outbound(
             vec![SocketAddr::new(dns_server, 53)],
@@ -96,11 +97,17 @@ pub unsafe extern "C" fn create_client(
             events_tx,
         ));
 
-        let handle_http_proxy =
-            serve_listener::<HttpProxyHandshaker<_>, _>(http_proxy_listener, outbound.clone());
+        let handle_http_proxy = serve_listener::<HttpProxyHandshaker<_>, _>(
+            http_proxy_listener,
+            outbound.clone(),
+            early_data,
+        );
 
-        let handle_socks5_proxy =
-            serve_listener::<SocksProxyHandshaker<_>, _>(socks5_proxy_listener, outbound);
+        let handle_socks5_proxy = serve_listener::<SocksProxyHandshaker<_>, _>(
+            socks5_proxy_listener,
+            outbound,
+            early_data,
+        );
 
         let handle_api_proxy = serve_stats(StatsProvider { events }, api_proxy_listener);
 
@@ -116,7 +123,7 @@ pub unsafe extern "C" fn create_client(
     match r {
         Ok(v) => Box::into_raw(Box::new(v)) as *mut c_void,
         Err(e) => {
-            if error != null_mut() && error_len > 0 {
+            if !error.is_null() && error_len > 0 {
                 // Write the error message to the provided buffer
                 let err_str = CString::new(format!("{e:?}")).unwrap();
                 let error_out =
@@ -1,3 +1,13 @@
+use anyhow::bail;
+use cpxy_ng::outbound::OutboundRequest;
+use cpxy_ng::udp::UdpTunnel;
+use std::net::IpAddr;
+
+pub enum ProxyCommand {
+    Connect(OutboundRequest),
+    UdpAssociate,
+}
+
 pub trait Handshaker<S>: Sized {
     type StreamType;
     type RequestType;
@@ -6,7 +16,19 @@ pub trait Handshaker<S>: Sized {
 
     fn respond_ok(self) -> impl Future<Output = anyhow::Result<Self::StreamType>> + Send;
 
-    fn respond_err(self, msg: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+    /// Tells the client the request failed, as precisely as the proxy protocol allows.
+    fn respond_err(self, err: &anyhow::Error) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Relays the datagrams of an accepted UDP association until the client is done with it.
+    /// Only datagrams from `client_ip`, where the request came from, are accepted.
+    fn respond_udp(
+        self,
+        _local_ip: IpAddr,
+        _client_ip: IpAddr,
+        _tunnel: UdpTunnel,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { bail!("UDP is not supported by this proxy") }
+    }
 
     fn stream_mut(&mut self) -> &mut Self::StreamType;
 }
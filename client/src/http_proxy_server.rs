@@ -1,8 +1,15 @@
-use crate::handshaker::Handshaker;
+use crate::handshaker::{Handshaker, ProxyCommand};
 use cpxy_ng::http_proxy::{ProxyRequest, parse_http_proxy_stream};
 use cpxy_ng::http_stream::HttpStream;
+use cpxy_ng::protocol::ErrorCode;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
+impl From<ProxyRequest> for ProxyCommand {
+    fn from(value: ProxyRequest) -> Self {
+        ProxyCommand::Connect(value.into())
+    }
+}
+
 pub struct HttpProxyHandshaker<S> {
     stream: HttpStream<(), S>,
     is_tunnel: bool,
@@ -35,12 +42,19 @@ where
         Ok(self.stream)
     }
 
-    async fn respond_err(mut self, msg: &str) -> anyhow::Result<()>
+    async fn respond_err(mut self, err: &anyhow::Error) -> anyhow::Result<()>
     where
         S: AsyncWrite + Unpin,
     {
+        let code = ErrorCode::of(err);
+        let status = match code {
+            ErrorCode::Forbidden => 403,
+            ErrorCode::TimedOut => 504,
+            _ => 502,
+        };
+
         self.stream
-            .write_all(construct_error_http_response(500, msg).as_bytes())
+            .write_all(construct_error_http_response(status, &format!("{err:#}")).as_bytes())
             .await?;
         Ok(())
     }
@@ -51,9 +65,19 @@ where
 }
 
 pub fn construct_error_http_response(code: u16, msg: &str) -> String {
+    let reason = match code {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Internal Error",
+    };
+
     format!(
-        "HTTP/1.1 {code} Internal Error\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-        msg.as_bytes().len(),
+        "HTTP/1.1 {code} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        msg.len(),
         msg
     )
 }
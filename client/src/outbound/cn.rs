@@ -4,16 +4,16 @@ use crate::outbound::{
 };
 use crate::protocol_config::Config;
 use crate::stats_server::OutboundEvent;
-use cpxy_ng::geoip::find_country_code_v4;
+use cpxy_ng::geoip::find_country_code_ip;
 use cpxy_ng::outbound::Outbound;
-use geoip_data::CN_GEOIP;
+use geoip_data::{CN_GEOIP, CN_GEOIP_V6};
 use hickory_resolver::Resolver;
 use hickory_resolver::config::{NameServerConfig, ResolverConfig};
 use hickory_resolver::name_server::TokioConnectionProvider;
 use hickory_resolver::proto::xfer::Protocol;
-use ipnet::Ipv4Net;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use std::borrow::Cow;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
@@ -26,18 +26,18 @@ pub fn cn_outbound(
 ) -> impl Outbound {
     let global_outbound = StatReportingOutbound {
         name: Cow::Borrowed("global"),
-        inner: ProtocolOutbound(main_server),
+        inner: ProtocolOutbound::new(main_server),
         events_tx: events_tx.clone(),
     };
 
     let ai_outbound = ai_server.map(|c| StatReportingOutbound {
         name: Cow::Borrowed("ai"),
-        inner: ProtocolOutbound(c),
+        inner: ProtocolOutbound::new(c),
         events_tx: events_tx.clone(),
     });
     let tailscale_outbound = tailscale_server.map(|c| StatReportingOutbound {
         name: Cow::Borrowed("tailscale"),
-        inner: ProtocolOutbound(c),
+        inner: ProtocolOutbound::new(c),
         events_tx: events_tx.clone(),
     });
 
@@ -74,15 +74,28 @@ pub fn cn_outbound(
 }
 
 const TAILSCALE_NETWORK: Ipv4Net = Ipv4Net::new_assert(Ipv4Addr::new(100, 0, 0, 0), 8);
+const TAILSCALE_NETWORK_V6: Ipv6Net =
+    Ipv6Net::new_assert(Ipv6Addr::new(0xfd7a, 0x115c, 0xa1e0, 0, 0, 0, 0, 0), 48);
 
-fn ip_should_route_direct(ip: Option<Ipv4Addr>) -> bool {
-    match ip {
+fn is_tailscale(ip: &IpAddr) -> bool {
+    IpNet::from(TAILSCALE_NETWORK).contains(ip) || IpNet::from(TAILSCALE_NETWORK_V6).contains(ip)
+}
+
+fn ip_should_route_direct(ip: Option<IpAddr>) -> bool {
+    match ip.map(|ip| ip.to_canonical()) {
         Some(ip) => {
-            ip.is_private()
+            let is_local = match ip {
+                IpAddr::V4(ip) => ip.is_private() || ip.is_link_local(),
+                IpAddr::V6(ip) => ip.is_unique_local() || ip.is_unicast_link_local(),
+            };
+
+            is_local
                 || ip.is_loopback()
-                || ip.is_link_local()
-                || TAILSCALE_NETWORK.contains(&ip)
-                || matches!(find_country_code_v4(&ip, CN_GEOIP), Ok(Some("CN")))
+                || is_tailscale(&ip)
+                || matches!(
+                    find_country_code_ip(&ip, CN_GEOIP, CN_GEOIP_V6),
+                    Ok(Some("CN"))
+                )
         }
         _ => true,
     }
@@ -92,9 +105,9 @@ fn site_should_route_ai(domain: &str) -> bool {
     domain.contains("openai.com") || domain.contains("gemini") || domain.contains("anthropic")
 }
 
-fn ip_should_route_tailscale(ip: Option<Ipv4Addr>) -> bool {
+fn ip_should_route_tailscale(ip: Option<IpAddr>) -> bool {
     match ip {
-        Some(ip) => TAILSCALE_NETWORK.contains(&ip),
+        Some(ip) => is_tailscale(&ip.to_canonical()),
         _ => false,
     }
 }
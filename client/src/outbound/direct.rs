@@ -1,6 +1,8 @@
 use anyhow::Context;
 use cpxy_ng::outbound::{Outbound, OutboundHost, OutboundRequest};
-use cpxy_ng::tls_stream::connect_tls;
+use cpxy_ng::tls_stream::{TlsOptions, connect_tls};
+use cpxy_ng::udp::{UdpTunnel, relay_to_socket};
+use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -10,12 +12,16 @@ use tracing::instrument;
 #[derive(Debug, Clone)]
 pub struct DirectOutbound {
     pub connection_timeout: Duration,
+    pub udp_idle_timeout: Duration,
+    pub tls_options: TlsOptions,
 }
 
 impl Default for DirectOutbound {
     fn default() -> Self {
         Self {
             connection_timeout: Duration::from_secs(10),
+            udp_idle_timeout: Duration::from_secs(60),
+            tls_options: TlsOptions::default(),
         }
     }
 }
@@ -32,11 +38,16 @@ impl Outbound for DirectOutbound {
         }: OutboundRequest,
     ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Send + Unpin + 'static> {
         let upstream = match &host {
-            OutboundHost::Resolved { ip: Some(ip), .. } => {
-                timeout(self.connection_timeout, TcpStream::connect((*ip, port)))
-                    .await
-                    .with_context(|| format!("Timeout connecting to {ip}:{port}"))?
-                    .with_context(|| format!("Failed to connect to {ip}:{port}"))?
+            OutboundHost::Resolved { domain, ips } if !ips.is_empty() => {
+                let addrs: Vec<SocketAddr> =
+                    ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                timeout(
+                    self.connection_timeout,
+                    TcpStream::connect(addrs.as_slice()),
+                )
+                .await
+                .with_context(|| format!("Timeout connecting to {domain}:{port}"))?
+                .with_context(|| format!("Failed to connect to {domain}:{port}"))?
             }
             OutboundHost::Domain(host) | OutboundHost::Resolved { domain: host, .. } => timeout(
                 self.connection_timeout,
@@ -47,7 +58,7 @@ impl Outbound for DirectOutbound {
             .with_context(|| format!("Failed to connect to {host}:{port}"))?,
         };
 
-        let mut upstream = connect_tls(host.host(), tls, upstream).await?;
+        let mut upstream = connect_tls(host.host(), tls, upstream, &self.tls_options).await?;
 
         if !initial_plaintext.is_empty() {
             upstream
@@ -58,4 +69,10 @@ impl Outbound for DirectOutbound {
 
         anyhow::Ok(upstream)
     }
+
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        let (local, remote) = UdpTunnel::pair();
+        tokio::spawn(relay_to_socket(remote, self.udp_idle_timeout, |_, _| true));
+        Ok(local)
+    }
 }
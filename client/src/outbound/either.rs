@@ -1,5 +1,6 @@
 use cpxy_ng::either_stream::EitherStream;
 use cpxy_ng::outbound::{Outbound, OutboundRequest};
+use cpxy_ng::udp::UdpTunnel;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub enum EitherOutbound<A, B> {
@@ -21,4 +22,11 @@ where
             EitherOutbound::Right(b) => b.send(req).await.map(EitherStream::Right),
         }
     }
+
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        match self {
+            EitherOutbound::Left(a) => a.send_udp().await,
+            EitherOutbound::Right(b) => b.send_udp().await,
+        }
+    }
 }
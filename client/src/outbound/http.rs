@@ -1,7 +1,7 @@
 use anyhow::{Context, ensure};
 use cpxy_ng::http_stream::HttpStream;
 use cpxy_ng::outbound::{Outbound, OutboundRequest};
-use cpxy_ng::tls_stream::connect_tls;
+use cpxy_ng::tls_stream::{TlsOptions, connect_tls};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 
@@ -9,6 +9,8 @@ pub struct HttpProxyOutbound {
     pub host: String,
     pub port: u16,
     pub tls: bool,
+    /// Used both for the proxy and the targets behind it
+    pub tls_options: TlsOptions,
 }
 
 impl Outbound for HttpProxyOutbound {
@@ -24,7 +26,7 @@ impl Outbound for HttpProxyOutbound {
         let upstream = TcpStream::connect((self.host.as_str(), self.port))
             .await
             .context("failed to connect to upstream")?;
-        let mut upstream = connect_tls(self.host.as_str(), self.tls, upstream)
+        let mut upstream = connect_tls(self.host.as_str(), self.tls, upstream, &self.tls_options)
             .await
             .context("failed to connect to upstream on tls")?;
 
@@ -51,7 +53,7 @@ impl Outbound for HttpProxyOutbound {
         .await
         .map_err(|(e, _)| e)?;
 
-        let mut upstream = connect_tls(host.host(), tls, upstream)
+        let mut upstream = connect_tls(host.host(), tls, upstream, &self.tls_options)
             .await
             .context("failed to connect to target on tls")?;
 
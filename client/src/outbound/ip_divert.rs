@@ -1,6 +1,7 @@
 use cpxy_ng::either_stream::EitherStream;
-use cpxy_ng::outbound::{Outbound, OutboundHost, OutboundRequest};
-use std::net::Ipv4Addr;
+use cpxy_ng::outbound::{Outbound, OutboundRequest};
+use cpxy_ng::udp::UdpTunnel;
+use std::net::IpAddr;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub struct IPDivertOutbound<O1, O2, F> {
@@ -13,23 +14,23 @@ impl<O1, O2, F> Outbound for IPDivertOutbound<O1, O2, F>
 where
     O1: Outbound + Sync,
     O2: Outbound + Sync,
-    F: Fn(Option<Ipv4Addr>) -> bool + Sync,
+    F: Fn(Option<IpAddr>) -> bool + Sync,
 {
     async fn send(
         &self,
         req: OutboundRequest,
     ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Send + Unpin + 'static> {
-        if let Some(outbound_a) = self.outbound_a.as_ref() {
-            let ip = match req.host {
-                OutboundHost::Domain(_) => None,
-                OutboundHost::Resolved { ip, .. } => ip,
-            };
-
-            if (self.should_use_a)(ip) {
-                return outbound_a.send(req).await.map(EitherStream::Left);
-            }
+        if let Some(outbound_a) = self.outbound_a.as_ref()
+            && (self.should_use_a)(req.host.ip())
+        {
+            return outbound_a.send(req).await.map(EitherStream::Left);
         }
 
         self.outbound_b.send(req).await.map(EitherStream::Right)
     }
+
+    /// Datagrams in an association can go anywhere, so they can't be diverted by IP.
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        self.outbound_b.send_udp().await
+    }
 }
@@ -1,34 +1,46 @@
 use crate::protocol_config::Config;
-use anyhow::{Context, bail};
-use cpxy_ng::cipher_select::select_cipher_based_on_port;
-use cpxy_ng::encrypt_stream::CipherStream;
+use anyhow::Context;
+use cpxy_ng::either_stream::EitherStream;
+use cpxy_ng::encrypt_stream::{CipherStream, Configuration};
+use cpxy_ng::h2::H2Session;
+use cpxy_ng::handshake::EphemeralKey;
 use cpxy_ng::key_util::random_vec;
-use cpxy_ng::outbound::{Outbound, OutboundRequest};
+use cpxy_ng::mux::{MuxSession, OpenRequest};
+use cpxy_ng::outbound::{Outbound, OutboundHost, OutboundRequest};
+use cpxy_ng::padding_stream::PaddedStream;
+use cpxy_ng::protocol::{TunnelMode, random_padding};
+use cpxy_ng::time_util::now_epoch_seconds;
 use cpxy_ng::tls_stream::connect_tls;
+use cpxy_ng::udp::UdpTunnel;
+use cpxy_ng::websocket_stream::{Role, WebSocketStream};
 use cpxy_ng::{http_protocol, protocol};
+use rand::random;
+use std::fmt::{Debug, Formatter};
 use std::io::Cursor;
+use std::net::IpAddr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 
-#[derive(Debug)]
-pub struct ProtocolOutbound(pub Config);
+pub struct ProtocolOutbound {
+    config: Config,
+    mux_session: Mutex<Option<MuxSession>>,
+    h2_session: Mutex<Option<H2Session>>,
+}
 
-impl Outbound for ProtocolOutbound {
-    #[tracing::instrument(
-        skip(initial_plaintext),
-        name = "send_protocol_outbound",
-        level = "info"
-    )]
-    async fn send(
+impl ProtocolOutbound {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            mux_session: Default::default(),
+            h2_session: Default::default(),
+        }
+    }
+
+    async fn connect_server(
         &self,
-        OutboundRequest {
-            host,
-            port,
-            tls,
-            initial_plaintext,
-        }: OutboundRequest,
     ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Send + Unpin + 'static> {
-        let config = &self.0;
+        let config = &self.config;
         let conn = TcpStream::connect((config.host.as_str(), config.port))
             .await
             .with_context(|| {
@@ -41,50 +53,270 @@ impl Outbound for ProtocolOutbound {
         conn.set_nodelay(true)
             .context("Error setting nodelay on TCP stream")?;
 
-        let mut conn = connect_tls(config.host.as_str(), config.tls, conn).await?;
-        let (client_send_cipher, server_send_cipher) = select_cipher_based_on_port(port);
+        connect_tls(config.host.as_str(), config.tls, conn, &config.tls_options).await
+    }
+
+    async fn connect(
+        &self,
+        mut request: protocol::Request,
+    ) -> anyhow::Result<(
+        protocol::Response,
+        impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    )> {
+        let config = &self.config;
+        request.websocket_framing = config.websocket_framing;
+        request.padding = random_padding(config.max_padding_len);
+        request.max_padding_len = config.max_padding_len;
+        request.padded_packets = config.padded_packets;
+
+        // Past its first bytes a partial cipher would leave the padding, and the lengths
+        // framing it, in clear
+        if config.max_padding_len > 0 && config.padded_packets > 0 {
+            for cipher in [
+                &mut request.client_send_cipher,
+                &mut request.server_send_cipher,
+            ] {
+                if matches!(cipher, Configuration::PartialEncrypt { .. }) {
+                    *cipher = Configuration::random_aead();
+                }
+            }
+        }
+
+        let client_send_cipher = request.client_send_cipher.clone();
+        let server_send_cipher = request.server_send_cipher.clone();
+
+        let ephemeral_key = config.forward_secrecy.then(EphemeralKey::random);
+        request.client_ephemeral_key = ephemeral_key.as_ref().map(EphemeralKey::public_key);
 
         let req = http_protocol::Request {
-            request: protocol::Request {
-                host: host.host().to_string(),
-                port,
-                tls,
-                client_send_cipher: client_send_cipher.clone(),
-                server_send_cipher: server_send_cipher.clone(),
-                initial_plaintext,
-                timestamp_epoch_seconds: 0,
-            },
-            websocket_key: random_vec(12),
+            request,
+            websocket_key: random_vec(16),
             host: config.host.clone(),
         };
 
-        req.send_over_http(&mut conn, &config.key)
-            .await
-            .context("Error sending request to upstream server")?;
+        let (response, conn) = if config.h2 {
+            let (headers, conn) = self
+                .h2_session()
+                .await?
+                .open(&req.to_h2_headers(&config.key, &config.camouflage)?)
+                .await
+                .context("Error sending request to upstream server")?;
 
-        let (http_protocol::Response { response, .. }, conn) =
-            http_protocol::Response::parse(conn, &config.key)
+            let response = http_protocol::Response::from_h2_headers(
+                &headers,
+                &config.key,
+                &config.camouflage,
+            )?;
+            (response.response, EitherStream::Left(conn))
+        } else {
+            let mut conn = self.connect_server().await?;
+            req.send_over_http(&mut conn, &config.key, &config.camouflage)
                 .await
-                .map_err(|(e, _)| e)?
-                .take_head();
+                .context("Error sending request to upstream server")?;
+
+            let (http_protocol::Response { response, .. }, conn) = http_protocol::Response::parse(
+                conn,
+                &config.key,
+                &config.camouflage,
+                &req.websocket_key,
+            )
+            .await
+            .map_err(|(e, _)| e)?
+            .take_head();
+            (response, EitherStream::Right(conn))
+        };
+
+        let (client_send_cipher, server_send_cipher) = match (ephemeral_key, &response) {
+            (
+                Some(ephemeral_key),
+                protocol::Response::Success {
+                    server_ephemeral_key,
+                    ..
+                },
+            ) => ephemeral_key.client_derive(
+                server_ephemeral_key
+                    .as_ref()
+                    .context("Server didn't answer the key exchange")?,
+                &client_send_cipher,
+                &server_send_cipher,
+            )?,
+            _ => (client_send_cipher, server_send_cipher),
+        };
+
+        let conn = if config.websocket_framing {
+            EitherStream::Left(WebSocketStream::new(conn, Role::Client))
+        } else {
+            EitherStream::Right(conn)
+        };
+
+        Ok((
+            response,
+            PaddedStream::new(
+                CipherStream::new(conn, &client_send_cipher, &server_send_cipher),
+                config.padded_packets,
+                config.max_padding_len,
+            ),
+        ))
+    }
+
+    /// The HTTP/2 connection all tunnels share, when the config asks for HTTP/2.
+    async fn h2_session(&self) -> anyhow::Result<H2Session> {
+        let mut session = self.h2_session.lock().await;
+        if let Some(s) = session.as_ref()
+            && !s.is_closed()
+        {
+            return Ok(s.clone());
+        }
+
+        let new_session = H2Session::client(self.connect_server().await?).await?;
+        tracing::info!("HTTP/2 connection established");
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+
+    async fn mux_session(&self) -> anyhow::Result<MuxSession> {
+        let mut session = self.mux_session.lock().await;
+        if let Some(s) = session.as_ref()
+            && !s.is_closed()
+        {
+            return Ok(s.clone());
+        }
+
+        let (response, conn) = self
+            .connect(session_request(TunnelMode::Mux))
+            .await
+            .context("Error establishing mux session")?;
+
+        if let protocol::Response::Error { code, .. } = response {
+            return Err(anyhow::Error::new(code)
+                .context("Error from server while establishing mux session"));
+        }
+
+        tracing::info!("Mux session established");
+        let new_session = MuxSession::client(conn);
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+}
+
+/// A request for a tunnel that carries its own targets, rather than connecting to one.
+fn session_request(mode: TunnelMode) -> protocol::Request {
+    protocol::Request {
+        mode,
+        host: Default::default(),
+        port: 0,
+        tls: false,
+        client_send_cipher: Configuration::random_aead(),
+        server_send_cipher: Configuration::random_aead(),
+        initial_plaintext: vec![],
+        initial_response_wait_ms: None,
+        timestamp_epoch_seconds: now_epoch_seconds(),
+        nonce: random(),
+        websocket_framing: false,
+        client_ephemeral_key: None,
+        padding: vec![],
+        max_padding_len: 0,
+        padded_packets: 0,
+        addresses: vec![],
+        trust_addresses: false,
+    }
+}
+
+impl Debug for ProtocolOutbound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ProtocolOutbound")
+            .field(&self.config)
+            .finish()
+    }
+}
+
+impl Outbound for ProtocolOutbound {
+    #[tracing::instrument(
+        skip(initial_plaintext),
+        name = "send_protocol_outbound",
+        level = "info"
+    )]
+    async fn send(
+        &self,
+        OutboundRequest {
+            host,
+            port,
+            tls,
+            initial_plaintext,
+        }: OutboundRequest,
+    ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Send + Unpin + 'static> {
+        let addresses: Vec<IpAddr> = match &host {
+            OutboundHost::Resolved { ips, .. } => ips.clone(),
+            OutboundHost::Domain(_) => vec![],
+        };
+
+        let (response, conn) = if self.config.mux {
+            let request = OpenRequest {
+                host: host.host().to_string(),
+                port,
+                addresses,
+                trust_addresses: self.config.trust_addresses,
+                tls,
+                initial_plaintext,
+                initial_response_wait_ms: self.config.initial_response_wait_ms,
+            };
+
+            let (response, conn) = self.mux_session().await?.open(&request).await?;
+            (response, EitherStream::Left(conn))
+        } else {
+            let (client_send_cipher, server_send_cipher) =
+                self.config.cipher_policy.select(port, &initial_plaintext);
+            let (response, conn) = self
+                .connect(protocol::Request {
+                    mode: TunnelMode::Stream,
+                    host: host.host().to_string(),
+                    port,
+                    tls,
+                    client_send_cipher,
+                    server_send_cipher,
+                    initial_plaintext,
+                    initial_response_wait_ms: self.config.initial_response_wait_ms,
+                    timestamp_epoch_seconds: now_epoch_seconds(),
+                    nonce: random(),
+                    websocket_framing: false,
+                    client_ephemeral_key: None,
+                    padding: vec![],
+                    max_padding_len: 0,
+                    padded_packets: 0,
+                    addresses,
+                    trust_addresses: self.config.trust_addresses,
+                })
+                .await?;
+            (response, EitherStream::Right(conn))
+        };
 
         match response {
             protocol::Response::Success {
                 initial_response, ..
             } => {
-                let (r, w) = tokio::io::split(CipherStream::new(
-                    conn,
-                    &client_send_cipher,
-                    &server_send_cipher,
-                ));
-
+                let (r, w) = tokio::io::split(conn);
                 let r = Cursor::new(initial_response).chain(r);
                 Ok(tokio::io::join(r, w))
             }
-            protocol::Response::Error { msg, .. } => {
-                tracing::info!("Server responded with error: {msg}");
-                bail!("Error from server: {msg}")
+            protocol::Response::Error { code, .. } => {
+                tracing::info!("Server responded with error: {code}");
+                Err(anyhow::Error::new(code).context("Error from server"))
             }
         }
     }
+
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        let (response, conn) = self
+            .connect(session_request(TunnelMode::Udp))
+            .await
+            .context("Error establishing UDP tunnel")?;
+
+        if let protocol::Response::Error { code, .. } = response {
+            return Err(
+                anyhow::Error::new(code).context("Error from server while establishing UDP tunnel")
+            );
+        }
+
+        Ok(UdpTunnel::over_stream(conn))
+    }
 }
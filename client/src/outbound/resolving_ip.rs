@@ -1,6 +1,7 @@
 use cpxy_ng::outbound::{Outbound, OutboundHost, OutboundRequest};
+use cpxy_ng::udp::UdpTunnel;
 use hickory_resolver::TokioResolver;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 
@@ -19,26 +20,30 @@ where
     ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Send + Unpin + 'static> {
         if let OutboundHost::Domain(host) = &mut req.host {
             // See the host is a raw IP to start with?
-            let mut ip: Option<Ipv4Addr> = host.parse().ok();
-
-            if ip.is_none() {
-                ip = self
+            let ips: Vec<IpAddr> = match host.parse() {
+                Ok(ip) => vec![ip],
+                // Looks up both A and AAAA records, as the resolver's strategy allows
+                Err(_) => self
                     .resolver
-                    .ipv4_lookup(host.as_str())
+                    .lookup_ip(host.as_str())
                     .await
-                    .map(|lookup| lookup.iter().next().map(|ip| ip.0))
+                    .map(|lookup| lookup.iter().collect())
                     .unwrap_or_else(|e| {
                         tracing::error!(?e, "failed to resolve domain: {host}");
-                        None
-                    });
-            }
+                        vec![]
+                    }),
+            };
 
             req.host = OutboundHost::Resolved {
                 domain: std::mem::take(host),
-                ip,
+                ips,
             };
         }
 
         self.inner.send(req).await
     }
+
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        self.inner.send_udp().await
+    }
 }
@@ -1,5 +1,6 @@
 use cpxy_ng::either_stream::EitherStream;
 use cpxy_ng::outbound::{Outbound, OutboundRequest};
+use cpxy_ng::udp::UdpTunnel;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 pub struct SiteDivertOutbound<O1, O2, F> {
@@ -25,4 +26,9 @@ where
             _ => self.outbound_b.send(req).await.map(EitherStream::Right),
         }
     }
+
+    /// Datagrams in an association can go anywhere, so they can't be diverted by site.
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        self.outbound_b.send_udp().await
+    }
 }
@@ -1,6 +1,7 @@
 use crate::stats_server::OutboundEvent;
 use anyhow::Context;
 use cpxy_ng::outbound::{Outbound, OutboundRequest};
+use cpxy_ng::udp::UdpTunnel;
 use std::borrow::Cow;
 use std::time::{Instant, SystemTime};
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -53,4 +54,8 @@ where
 
         r
     }
+
+    async fn send_udp(&self) -> anyhow::Result<UdpTunnel> {
+        self.inner.send_udp().await
+    }
 }
@@ -1,24 +1,71 @@
-use crate::handshaker::Handshaker;
+use crate::handshaker::{Handshaker, ProxyCommand};
 use anyhow::Context;
 use cpxy_ng::outbound::{Outbound, OutboundRequest};
+use std::net::IpAddr;
+use std::time::Duration;
 use tokio::io::copy_bidirectional;
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task::JoinSet;
 
-pub async fn serve<HS, S, OB>(stream: S, outbound: OB) -> anyhow::Result<()>
+/// Serves a proxy client. With `early_data`, a connection request is answered right away and
+/// the application's first bytes, if they come within that time, go along with the request.
+/// Failures to connect upstream can then no longer be reported to the application.
+pub async fn serve<HS, S, OB>(
+    stream: S,
+    local_ip: IpAddr,
+    client_ip: IpAddr,
+    outbound: OB,
+    early_data: Option<Duration>,
+) -> anyhow::Result<()>
 where
     HS: Handshaker<S>,
-    <HS as Handshaker<S>>::RequestType: Into<OutboundRequest>,
+    <HS as Handshaker<S>>::RequestType: Into<ProxyCommand>,
     <HS as Handshaker<S>>::StreamType: AsyncRead + AsyncWrite + Unpin,
     OB: Outbound,
 {
     let (req, handshake) = HS::accept(stream).await?;
 
-    let req: OutboundRequest = req.into();
+    let mut req: OutboundRequest = match req.into() {
+        ProxyCommand::Connect(req) => req,
+        ProxyCommand::UdpAssociate => {
+            return match outbound.send_udp().await {
+                Ok(tunnel) => handshake.respond_udp(local_ip, client_ip, tunnel).await,
+                Err(e) => {
+                    let e = e.context("Error starting UDP association");
+                    handshake
+                        .respond_err(&e)
+                        .await
+                        .context("Error responding err")?;
+                    Err(e)
+                }
+            };
+        }
+    };
+
     let mut conn: <HS as Handshaker<S>>::StreamType;
     let mut upstream;
 
+    if let Some(wait) = early_data.filter(|_| req.initial_plaintext.is_empty()) {
+        conn = handshake
+            .respond_ok()
+            .await
+            .context("Error responding ok")?;
+
+        let mut buf = vec![0u8; 4096];
+        match tokio::time::timeout(wait, conn.read(&mut buf)).await {
+            Ok(Ok(0)) => return Ok(()),
+            Ok(Ok(n)) => buf.truncate(n),
+            Ok(Err(e)) => return Err(e).context("Error reading early data"),
+            Err(_) => buf.clear(), // The application waits for the server to speak first
+        }
+        req.initial_plaintext = buf;
+
+        upstream = outbound.send(req).await.context("Error sending upstream")?;
+        let _ = copy_bidirectional(&mut conn, &mut upstream).await;
+        return Ok(());
+    }
+
     match outbound.send(req).await {
         Ok(up) => {
             upstream = up;
@@ -29,8 +76,9 @@ where
         }
 
         Err(e) => {
+            let e = e.context("Error sending upstream");
             handshake
-                .respond_err(&format!("Error sending upstream: {e}"))
+                .respond_err(&e)
                 .await
                 .context("Error responding err")?;
             return Err(e);
@@ -41,10 +89,14 @@ where
     Ok(())
 }
 
-pub async fn serve_listener<HS, OB>(listener: TcpListener, outbound: OB) -> anyhow::Result<()>
+pub async fn serve_listener<HS, OB>(
+    listener: TcpListener,
+    outbound: OB,
+    early_data: Option<Duration>,
+) -> anyhow::Result<()>
 where
     HS: Handshaker<TcpStream> + Send + 'static,
-    <HS as Handshaker<TcpStream>>::RequestType: Into<OutboundRequest>,
+    <HS as Handshaker<TcpStream>>::RequestType: Into<ProxyCommand>,
     <HS as Handshaker<TcpStream>>::StreamType: AsyncRead + AsyncWrite + Unpin + Send,
     OB: Outbound + Clone + Send + 'static,
 {
@@ -52,6 +104,13 @@ where
     loop {
         let (stream, addr) = listener.accept().await?;
         tracing::info!(?addr, "Accepted connection");
-        js.spawn(serve::<HS, _, _>(stream, outbound.clone()));
+        let local_ip = stream.local_addr()?.ip();
+        js.spawn(serve::<HS, _, _>(
+            stream,
+            local_ip,
+            addr.ip(),
+            outbound.clone(),
+            early_data,
+        ));
     }
 }
@@ -1,9 +1,14 @@
-use crate::handshaker::Handshaker;
+use crate::handshaker::{Handshaker, ProxyCommand};
 use anyhow::{Context, bail, ensure};
+use bytes::Bytes;
 use cpxy_ng::outbound::{OutboundHost, OutboundRequest};
+use cpxy_ng::protocol::ErrorCode;
+use cpxy_ng::udp::{Datagram, UdpTunnel};
 use std::fmt::{Debug, Formatter};
 use std::net::{IpAddr, SocketAddr};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UdpSocket;
+use tokio::select;
 use tracing::instrument;
 
 pub struct SocksProxyHandshaker<S> {
@@ -16,37 +21,36 @@ impl<S> Debug for SocksProxyHandshaker<S> {
     }
 }
 
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+
 #[derive(Debug)]
 pub enum ProxyRequest {
     WithDomain(String, u16),
     WithIP(SocketAddr),
+    UdpAssociate,
 }
 
-impl From<ProxyRequest> for OutboundRequest {
+impl From<ProxyRequest> for ProxyCommand {
     fn from(value: ProxyRequest) -> Self {
-        match value {
-            ProxyRequest::WithDomain(host, port) => Self {
+        ProxyCommand::Connect(match value {
+            ProxyRequest::WithDomain(host, port) => OutboundRequest {
                 host: OutboundHost::Domain(host),
                 port,
                 tls: false,
                 initial_plaintext: vec![],
             },
-            ProxyRequest::WithIP(SocketAddr::V4(addr)) => Self {
+            ProxyRequest::WithIP(addr) => OutboundRequest {
                 host: OutboundHost::Resolved {
                     domain: addr.ip().to_string(),
-                    ip: Some(*addr.ip()),
+                    ips: vec![addr.ip()],
                 },
                 port: addr.port(),
                 tls: false,
                 initial_plaintext: vec![],
             },
-            ProxyRequest::WithIP(addr) => Self {
-                host: OutboundHost::Domain(addr.ip().to_string()),
-                port: addr.port(),
-                tls: false,
-                initial_plaintext: vec![],
-            },
-        }
+            ProxyRequest::UdpAssociate => return ProxyCommand::UdpAssociate,
+        })
     }
 }
 
@@ -86,9 +90,10 @@ where
             "Unsupported SOCKS version while waiting for request"
         );
 
+        let command = stream.read_u8().await.context("Error reading command")?;
         ensure!(
-            stream.read_u8().await.context("Error reading command")? == 1,
-            "Only CONNECT command is supported"
+            command == CMD_CONNECT || command == CMD_UDP_ASSOCIATE,
+            "Unsupported command {command}"
         );
 
         ensure!(
@@ -149,6 +154,12 @@ where
             }
         };
 
+        if command == CMD_UDP_ASSOCIATE {
+            // The address is where the client will send from, which we learn from the
+            // first datagram anyway
+            return anyhow::Ok((ProxyRequest::UdpAssociate, Self { stream }));
+        }
+
         anyhow::Ok((dest, Self { stream }))
     }
 
@@ -165,14 +176,229 @@ where
         Ok(self.stream)
     }
 
-    async fn respond_err(self, _msg: &str) -> anyhow::Result<()>
+    async fn respond_err(mut self, err: &anyhow::Error) -> anyhow::Result<()>
     where
         S: AsyncWrite + Unpin,
     {
+        let reply = match ErrorCode::of(err) {
+            ErrorCode::Forbidden => 0x02,
+            ErrorCode::NetworkUnreachable => 0x03,
+            ErrorCode::HostNotFound => 0x04,
+            ErrorCode::ConnectionRefused => 0x05,
+            ErrorCode::TimedOut => 0x06,
+            ErrorCode::TlsFailure | ErrorCode::Other => 0x01,
+        };
+
+        self.stream
+            .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .context("Error writing failure reply")?;
         Ok(())
     }
 
+    async fn respond_udp(
+        mut self,
+        local_ip: IpAddr,
+        client_ip: IpAddr,
+        tunnel: UdpTunnel,
+    ) -> anyhow::Result<()> {
+        let socket = UdpSocket::bind((local_ip, 0))
+            .await
+            .context("Error binding UDP relay socket")?;
+
+        let mut reply = vec![5, 0, 0];
+        write_address(
+            &mut reply,
+            &socket
+                .local_addr()
+                .context("Error getting UDP relay address")?,
+        );
+        self.stream
+            .write_all(&reply)
+            .await
+            .context("Error writing success reply")?;
+
+        let UdpTunnel { tx, mut rx } = tunnel;
+        let mut client_addr: Option<SocketAddr> = None;
+        let mut buf = vec![0u8; 65536];
+        let mut control_buf = [0u8; 64];
+
+        loop {
+            select! {
+                r = socket.recv_from(&mut buf) => {
+                    let (n, from) = r.context("Error receiving from UDP relay socket")?;
+
+                    // As RFC 1928 asks, only the host that made the request may use the
+                    // association. Its port we learn from the first datagram.
+                    if from.ip().to_canonical() != client_ip.to_canonical()
+                        || *client_addr.get_or_insert(from) != from
+                    {
+                        continue;
+                    }
+
+                    match parse_udp_request(&buf[..n]) {
+                        Ok(datagram) => {
+                            if tx.send(datagram).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Err(e) => tracing::debug!(?e, "Dropping invalid SOCKS5 UDP request"),
+                    }
+                }
+
+                datagram = rx.recv() => {
+                    let Some(datagram) = datagram else {
+                        return Ok(());
+                    };
+
+                    if let Some(client_addr) = client_addr {
+                        let _ = socket.send_to(&encode_udp_reply(&datagram), client_addr).await;
+                    }
+                }
+
+                // The association lives as long as the control connection
+                r = self.stream.read(&mut control_buf) => {
+                    if r.context("Error reading control connection")? == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
     fn stream_mut(&mut self) -> &mut Self::StreamType {
         &mut self.stream
     }
 }
+
+fn write_address(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(1);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(4);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn parse_udp_request(packet: &[u8]) -> anyhow::Result<Datagram> {
+    ensure!(packet.len() > 4, "Packet too short");
+    ensure!(packet[2] == 0, "Fragmented datagrams are not supported");
+
+    let (host, rest) = match packet[3] {
+        1 => {
+            ensure!(
+                packet.len() >= 4 + 4 + 2,
+                "Packet too short for IPv4 address"
+            );
+            let ip: [u8; 4] = packet[4..8].try_into().unwrap();
+            (IpAddr::from(ip).to_string(), &packet[8..])
+        }
+        3 => {
+            let len = packet[4] as usize;
+            ensure!(packet.len() >= 5 + len + 2, "Packet too short for domain");
+            let domain =
+                std::str::from_utf8(&packet[5..5 + len]).context("Invalid UTF-8 in domain")?;
+            (domain.to_string(), &packet[5 + len..])
+        }
+        4 => {
+            ensure!(
+                packet.len() >= 4 + 16 + 2,
+                "Packet too short for IPv6 address"
+            );
+            let ip: [u8; 16] = packet[4..20].try_into().unwrap();
+            (IpAddr::from(ip).to_string(), &packet[20..])
+        }
+        addr_type => bail!("Unsupported address type {addr_type}"),
+    };
+
+    Ok(Datagram {
+        host,
+        port: u16::from_be_bytes([rest[0], rest[1]]),
+        payload: Bytes::copy_from_slice(&rest[2..]),
+    })
+}
+
+fn encode_udp_reply(datagram: &Datagram) -> Vec<u8> {
+    let mut buf = vec![0, 0, 0];
+    match datagram.host.parse::<IpAddr>() {
+        Ok(ip) => write_address(&mut buf, &SocketAddr::new(ip, datagram.port)),
+        Err(_) => {
+            buf.push(3);
+            buf.push(datagram.host.len() as u8);
+            buf.extend_from_slice(datagram.host.as_bytes());
+            buf.extend_from_slice(&datagram.port.to_be_bytes());
+        }
+    }
+    buf.extend_from_slice(&datagram.payload);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn udp_request_parsing_works() {
+        for host in ["1.2.3.4", "example.com", "::1"] {
+            let datagram = Datagram {
+                host: host.to_string(),
+                port: 53,
+                payload: Bytes::from_static(b"hello"),
+            };
+
+            // Requests and replies share the same layout
+            let parsed = parse_udp_request(&encode_udp_reply(&datagram)).expect("To parse");
+            assert_eq!(parsed, datagram);
+        }
+
+        assert!(parse_udp_request(&[0, 0, 1, 1, 1, 2, 3, 4, 0, 53]).is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_association_only_serves_client() {
+        let (control, mut control_peer) = tokio::io::duplex(64);
+        let handshaker = SocksProxyHandshaker {
+            stream: BufReader::new(control),
+        };
+        let (local, mut remote) = UdpTunnel::pair();
+        let relay = tokio::spawn(handshaker.respond_udp(
+            "127.0.0.1".parse().unwrap(),
+            "127.0.0.1".parse().unwrap(),
+            local,
+        ));
+
+        let mut reply = [0u8; 10];
+        control_peer.read_exact(&mut reply).await.unwrap();
+        let relay_addr =
+            SocketAddr::from(([127, 0, 0, 1], u16::from_be_bytes([reply[8], reply[9]])));
+
+        let request = |host: &str| {
+            encode_udp_reply(&Datagram {
+                host: host.to_string(),
+                port: 53,
+                payload: Bytes::from_static(b"query"),
+            })
+        };
+
+        // Someone else on the network can't take over the association
+        let stranger = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        stranger
+            .send_to(&request("1.1.1.1"), relay_addr)
+            .await
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&request("8.8.8.8"), relay_addr)
+            .await
+            .unwrap();
+        assert_eq!(remote.rx.recv().await.unwrap().host, "8.8.8.8");
+
+        drop(control_peer);
+        relay.await.unwrap().unwrap();
+    }
+}
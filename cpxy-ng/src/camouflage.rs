@@ -0,0 +1,269 @@
+use anyhow::{Context, bail, ensure};
+use rand::random_range;
+use rand::seq::IndexedRandom;
+use std::borrow::Cow;
+
+/// How the encoded request is laid out in the URL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathEncoding {
+    /// Split into path segments at random points
+    Segments,
+    /// Ids between resource names, like `/users/<id>/posts/<id>`
+    Rest,
+    /// Values of a query string, like `/search?q=<value>&page=<value>`
+    Query,
+}
+
+/// What the requests and responses look like on the wire. The client and the server must
+/// agree on it, so it's configured the same way on both: as query pairs of the client's URL,
+/// or the server's `--camouflage` option.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camouflage {
+    /// Every request path starts with this, so the server can live behind a reverse proxy
+    /// location. Empty for the root.
+    pub path_prefix: String,
+    pub path_encoding: PathEncoding,
+    /// The header carrying what of the request doesn't fit in the URL
+    pub request_header: String,
+    /// The header carrying the response
+    pub response_header: String,
+    /// HTTP/1.1 request methods to pick from
+    pub methods: Vec<String>,
+    pub user_agents: Vec<String>,
+}
+
+static USER_AGENTS: &[&str] = &[
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/139.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/139.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/138.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/138.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:141.0) Gecko/20100101 Firefox/141.0",
+];
+
+static RESOURCES: &[&str] = &[
+    "users", "posts", "items", "orders", "files", "comments", "assets", "sessions",
+];
+
+static PAGES: &[&str] = &["search", "list", "view", "index", "browse"];
+
+static QUERY_KEYS: &[&str] = &["q", "id", "page", "ref", "sid", "token", "v", "cursor"];
+
+// How much of the encoded request goes in the URL, the rest goes in the request header
+const MAX_PATH_DATA_LEN: usize = 25;
+
+impl Default for Camouflage {
+    fn default() -> Self {
+        Self {
+            path_prefix: String::new(),
+            path_encoding: PathEncoding::Segments,
+            request_header: "Authorization".to_string(),
+            response_header: "X-Cache-Result".to_string(),
+            methods: ["GET", "POST", "PATCH", "PUT"].map(String::from).to_vec(),
+            user_agents: USER_AGENTS.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+fn ensure_token(name: &str, what: &str) -> anyhow::Result<()> {
+    ensure!(
+        !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'),
+        "Invalid {what}: {name:?}"
+    );
+    Ok(())
+}
+
+impl Camouflage {
+    /// Reads a profile from query pairs, ignoring the keys it doesn't know: `path_prefix`,
+    /// `path_encoding` (segments, rest or query), `req_header`, `resp_header`, `methods`
+    /// (comma separated) and `ua` (repeated for each user agent).
+    pub fn from_query_pairs<'a>(
+        pairs: impl IntoIterator<Item = (Cow<'a, str>, Cow<'a, str>)>,
+    ) -> anyhow::Result<Self> {
+        let mut camouflage = Self::default();
+        let mut user_agents = vec![];
+
+        for (key, value) in pairs {
+            match key.as_ref() {
+                "path_prefix" => {
+                    let prefix = value.trim_end_matches('/');
+                    ensure!(
+                        prefix.is_empty() || prefix.starts_with('/'),
+                        "Expected path_prefix to start with /"
+                    );
+                    ensure!(
+                        !prefix.contains(['?', '#']),
+                        "Expected path_prefix to be a plain path"
+                    );
+                    camouflage.path_prefix = prefix.to_string();
+                }
+                "path_encoding" => {
+                    camouflage.path_encoding = match value.as_ref() {
+                        "segments" => PathEncoding::Segments,
+                        "rest" => PathEncoding::Rest,
+                        "query" => PathEncoding::Query,
+                        v => bail!("Unknown path_encoding: {v}, expected segments, rest or query"),
+                    }
+                }
+                "req_header" => {
+                    ensure_token(&value, "req_header")?;
+                    camouflage.request_header = value.into_owned();
+                }
+                "resp_header" => {
+                    ensure_token(&value, "resp_header")?;
+                    camouflage.response_header = value.into_owned();
+                }
+                "methods" => {
+                    let methods = value
+                        .split(',')
+                        .map(|m| m.trim().to_ascii_uppercase())
+                        .collect::<Vec<_>>();
+                    for method in &methods {
+                        ensure_token(method, "method")?;
+                    }
+                    camouflage.methods = methods;
+                }
+                "ua" => user_agents.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        if !user_agents.is_empty() {
+            camouflage.user_agents = user_agents;
+        }
+        Ok(camouflage)
+    }
+
+    /// Parses a profile given as a query string, such as `path_prefix=/api&path_encoding=rest`.
+    pub fn from_query_str(query: &str) -> anyhow::Result<Self> {
+        Self::from_query_pairs(url::form_urlencoded::parse(query.as_bytes()))
+    }
+
+    pub fn random_method(&self) -> &str {
+        self.methods.choose(&mut rand::rng()).map_or("GET", |s| s)
+    }
+
+    pub fn random_user_agent(&self) -> Option<&str> {
+        self.user_agents
+            .choose(&mut rand::rng())
+            .map(|s| s.as_str())
+    }
+
+    /// Lays out an encoded request (URL-safe base64) as a request path, plus what's left for
+    /// the request header.
+    pub fn encode_path(&self, encoded: &str) -> (String, String) {
+        let (data, overflow) = encoded.split_at(encoded.len().min(MAX_PATH_DATA_LEN));
+        let mut path = self.path_prefix.clone();
+
+        match self.path_encoding {
+            PathEncoding::Segments => {
+                let mut segments = data.to_string();
+                let mut pos = 1;
+                while pos + 2 < segments.len() {
+                    let insertion_point = random_range(pos..segments.len());
+                    segments.insert(insertion_point, '/');
+                    pos = insertion_point + 2;
+                }
+                path.push('/');
+                path.push_str(&segments);
+            }
+
+            PathEncoding::Rest => {
+                for chunk in random_chunks(data) {
+                    path.push('/');
+                    path.push_str(RESOURCES.choose(&mut rand::rng()).unwrap());
+                    path.push('/');
+                    path.push_str(chunk);
+                }
+            }
+
+            PathEncoding::Query => {
+                path.push('/');
+                path.push_str(PAGES.choose(&mut rand::rng()).unwrap());
+                let chunks = random_chunks(data);
+                let keys = QUERY_KEYS.choose_multiple(&mut rand::rng(), chunks.len());
+                for (i, (key, chunk)) in keys.zip(chunks).enumerate() {
+                    path.push(if i == 0 { '?' } else { '&' });
+                    path.push_str(key);
+                    path.push('=');
+                    path.push_str(chunk);
+                }
+            }
+        }
+
+        (path, overflow.to_string())
+    }
+
+    /// Recovers the encoded request from a request path and the request header.
+    pub fn decode_path(&self, path: &str, overflow: Option<&str>) -> anyhow::Result<String> {
+        let rest = path
+            .strip_prefix(self.path_prefix.as_str())
+            .filter(|rest| rest.starts_with('/'))
+            .context("Request path doesn't start with the path prefix")?;
+
+        let mut encoded = match self.path_encoding {
+            PathEncoding::Segments => rest.replace('/', ""),
+            PathEncoding::Rest => rest.split('/').skip(2).step_by(2).collect::<String>(),
+            PathEncoding::Query => {
+                let (_, query) = rest.split_once('?').context("Expected a query string")?;
+                url::form_urlencoded::parse(query.as_bytes())
+                    .map(|(_, v)| v.into_owned())
+                    .collect::<String>()
+            }
+        };
+
+        encoded.push_str(overflow.unwrap_or_default());
+        Ok(encoded)
+    }
+}
+
+/// Splits `data` into a few pieces of random length, like ids or search terms would be.
+fn random_chunks(mut data: &str) -> Vec<&str> {
+    let mut chunks = vec![];
+    while !data.is_empty() {
+        let (chunk, rest) = data.split_at(random_range(6..=12).min(data.len()));
+        chunks.push(chunk);
+        data = rest;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_encodings_round_trip() {
+        let encoded = "AbCdEfGhIjKlMnOpQrStUvWxYz0123456789-_abcdef";
+        for path_encoding in ["segments", "rest", "query"] {
+            let camouflage = Camouflage::from_query_str(&format!(
+                "path_prefix=/api/v1/&path_encoding={path_encoding}"
+            ))
+            .unwrap();
+
+            let (path, overflow) = camouflage.encode_path(encoded);
+            assert!(path.starts_with("/api/v1/"), "{path}");
+            assert_eq!(
+                camouflage.decode_path(&path, Some(&overflow)).unwrap(),
+                encoded,
+                "{path}"
+            );
+            assert!(camouflage.decode_path(&path[4..], Some(&overflow)).is_err());
+        }
+    }
+
+    #[test]
+    fn camouflage_parsing_works() {
+        let camouflage = Camouflage::from_query_str(
+            "req_header=X-Token&methods=get,post&ua=curl%2F8.0&ua=Wget&mux=true",
+        )
+        .unwrap();
+        assert_eq!(camouflage.request_header, "X-Token");
+        assert_eq!(camouflage.methods, ["GET", "POST"]);
+        assert_eq!(camouflage.user_agents, ["curl/8.0", "Wget"]);
+        assert_eq!(camouflage.path_prefix, "");
+
+        assert!(Camouflage::from_query_str("req_header=Bad Header").is_err());
+        assert!(Camouflage::from_query_str("path_prefix=api").is_err());
+        assert!(Camouflage::from_query_str("path_encoding=json").is_err());
+    }
+}
@@ -1,12 +1,101 @@
 use crate::encrypt_stream::Configuration;
+use anyhow::bail;
 use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+/// How the client picks the ciphers of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CipherPolicy {
+    /// Partial encryption if the application starts with a TLS ClientHello, full otherwise.
+    /// The port decides when the application hasn't sent anything yet, so on CONNECT and
+    /// SOCKS this relies on the client being configured to wait for early data.
+    #[default]
+    Content,
+    /// Partial encryption on the usual TLS ports, full otherwise
+    Port,
+    /// Always full encryption
+    Full,
+}
+
+impl FromStr for CipherPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "content" => Ok(Self::Content),
+            "port" => Ok(Self::Port),
+            "full" => Ok(Self::Full),
+            s => bail!("Unknown cipher policy: {s}, expected content, port or full"),
+        }
+    }
+}
+
+impl CipherPolicy {
+    /// Picks the client and server send ciphers for a stream to `port` that starts with
+    /// `initial_data` from the application.
+    pub fn select(&self, port: u16, initial_data: &[u8]) -> (Configuration, Configuration) {
+        match self {
+            Self::Content if !initial_data.is_empty() => {
+                if is_tls_client_hello(initial_data) {
+                    partial_ciphers()
+                } else {
+                    full_ciphers()
+                }
+            }
+            Self::Content | Self::Port => select_cipher_based_on_port(port),
+            Self::Full => full_ciphers(),
+        }
+    }
+}
+
+/// Whether `data` starts with a TLS record carrying a ClientHello. Only the first few bytes
+/// are looked at, so a ClientHello cut short still counts.
+pub fn is_tls_client_hello(data: &[u8]) -> bool {
+    // Record type handshake, major version 3, then the handshake type ClientHello right
+    // after the 5-byte record header
+    matches!(data, [0x16, 0x03, minor, _, _, 0x01, ..] if *minor <= 0x04)
+}
+
+fn partial_ciphers() -> (Configuration, Configuration) {
+    (
+        Configuration::random_partial(NonZeroUsize::new(32).unwrap()),
+        Configuration::random_partial(NonZeroUsize::new(512).unwrap()),
+    )
+}
+
+fn full_ciphers() -> (Configuration, Configuration) {
+    (Configuration::random_aead(), Configuration::random_aead())
+}
 
 pub fn select_cipher_based_on_port(port: u16) -> (Configuration, Configuration) {
     match port {
-        443 | 465 | 993 | 5223 => (
-            Configuration::random_partial(NonZeroUsize::new(32).unwrap()),
-            Configuration::random_partial(NonZeroUsize::new(512).unwrap()),
-        ),
-        _ => (Configuration::random_full(), Configuration::random_full()),
+        443 | 465 | 993 | 5223 => partial_ciphers(),
+        _ => full_ciphers(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_policy_works() {
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc];
+        let is_partial = |(c, _): (Configuration, Configuration)| {
+            matches!(c, Configuration::PartialEncrypt { .. })
+        };
+
+        assert!(is_partial(
+            CipherPolicy::Content.select(8443, &client_hello)
+        ));
+        assert!(!is_partial(
+            CipherPolicy::Content.select(443, b"GET / HTTP/1.1\r\n")
+        ));
+        assert!(is_partial(CipherPolicy::Content.select(443, &[])));
+        assert!(!is_partial(CipherPolicy::Port.select(8443, &client_hello)));
+        assert!(!is_partial(CipherPolicy::Full.select(443, &client_hello)));
+
+        assert!(!is_tls_client_hello(&client_hello[..5]));
+        assert!(!is_tls_client_hello(&[0x17, 0x03, 0x03, 0x00, 0x10, 0x01]));
     }
 }
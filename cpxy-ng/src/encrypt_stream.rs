@@ -1,11 +1,14 @@
-use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use bytes::{Buf, BytesMut};
 use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
 use rand::random;
 use rkyv::{Archive, Deserialize, Serialize};
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 use std::num::NonZeroUsize;
 use std::pin::Pin;
-use std::task::{ready, Context, Poll};
+use std::task::{Context, Poll, ready};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
@@ -20,6 +23,11 @@ pub enum Configuration {
         key: [u8; 32],
         nonce: [u8; 12],
     },
+    /// Data is sent in authenticated chunks: a sealed 2-byte length followed by the sealed payload,
+    /// each with its own counter nonce.
+    AeadEncrypt {
+        key: [u8; 32],
+    },
 }
 
 impl Configuration {
@@ -37,9 +45,144 @@ impl Configuration {
             enc_size,
         }
     }
+
+    pub fn random_aead() -> Self {
+        Self::AeadEncrypt { key: random() }
+    }
+}
+
+const AEAD_TAG_LEN: usize = 16;
+const AEAD_LEN_SIZE: usize = 2;
+const MAX_AEAD_CHUNK_LEN: usize = 0x3FFF;
+
+struct AeadState {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    // Sealed bytes: waiting to be written when sending, or waiting to be opened when receiving
+    sealed: BytesMut,
+    // Opened bytes not yet read, only used when receiving
+    opened: BytesMut,
+    // Payload length of the chunk being received, once its length has been opened
+    payload_len: Option<usize>,
 }
 
+impl AeadState {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+            sealed: BytesMut::new(),
+            opened: BytesMut::new(),
+            payload_len: None,
+        }
+    }
+
+    fn next_nonce(&mut self) -> chacha20poly1305::Nonce {
+        let mut nonce = chacha20poly1305::Nonce::default();
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> std::io::Result<()> {
+        let len = (plaintext.len() as u16).to_be_bytes();
+        for part in [&len[..], plaintext] {
+            let nonce = self.next_nonce();
+            let sealed = self
+                .cipher
+                .encrypt(&nonce, part)
+                .map_err(|e| Error::other(e.to_string()))?;
+            self.sealed.extend_from_slice(&sealed);
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, len: usize) -> std::io::Result<Vec<u8>> {
+        let sealed = self.sealed.split_to(len);
+        let nonce = self.next_nonce();
+        self.cipher
+            .decrypt(&nonce, sealed.as_ref())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Chunk failed authentication"))
+    }
+
+    /// Opens the next chunk if it has been fully received, returning whether it has.
+    fn open_chunk(&mut self) -> std::io::Result<bool> {
+        let payload_len = match self.payload_len {
+            Some(v) => v,
+            None if self.sealed.len() < AEAD_LEN_SIZE + AEAD_TAG_LEN => return Ok(false),
+            None => {
+                let len = self.open(AEAD_LEN_SIZE + AEAD_TAG_LEN)?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                if len > MAX_AEAD_CHUNK_LEN {
+                    return Err(Error::new(ErrorKind::InvalidData, "Chunk too large"));
+                }
+                self.payload_len = Some(len);
+                len
+            }
+        };
+
+        if self.sealed.len() < payload_len + AEAD_TAG_LEN {
+            return Ok(false);
+        }
+
+        let payload = self.open(payload_len + AEAD_TAG_LEN)?;
+        self.opened.extend_from_slice(&payload);
+        self.payload_len = None;
+        Ok(true)
+    }
+
+    fn poll_read<S: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut S,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            if !self.opened.is_empty() {
+                let n = self.opened.len().min(buf.remaining());
+                buf.put_slice(&self.opened.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.open_chunk()? {
+                continue;
+            }
+
+            let mut read_buf = [0u8; 8192];
+            let mut read_buf = ReadBuf::new(&mut read_buf);
+            ready!(Pin::new(&mut *stream).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                if self.sealed.is_empty() && self.payload_len.is_none() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Stream ended in the middle of a chunk",
+                )));
+            }
+            self.sealed.extend_from_slice(read_buf.filled());
+        }
+    }
+
+    fn poll_drain<S: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut S,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while !self.sealed.is_empty() {
+            let n = ready!(Pin::new(&mut *stream).poll_write(cx, &self.sealed))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.sealed.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Default)]
 enum CipherState<B> {
+    #[default]
     None,
     Partial {
         remaining: NonZeroUsize,
@@ -47,34 +190,34 @@ enum CipherState<B> {
         buffer: B,
     },
     Full(ChaCha20, B),
+    Aead(AeadState),
 }
 
 impl<B> CipherState<B> {
     fn new(config: &Configuration, buffer: B) -> Self {
         match config {
             Configuration::Plaintext => Self::None,
-            Configuration::PartialEncrypt { key, nonce, enc_size } => {
+            Configuration::PartialEncrypt {
+                key,
+                nonce,
+                enc_size,
+            } => {
                 let cipher = ChaCha20::new(key.into(), nonce.into());
                 Self::Partial {
                     remaining: *enc_size,
                     cipher,
                     buffer,
                 }
-            },
+            }
             Configuration::FullEncrypt { key, nonce } => {
                 let cipher = ChaCha20::new(key.into(), nonce.into());
                 Self::Full(cipher, buffer)
-            },
+            }
+            Configuration::AeadEncrypt { key } => Self::Aead(AeadState::new(key)),
         }
     }
 }
 
-impl<B> Default for CipherState<B> {
-    fn default() -> Self {
-        Self::None
-    }
-}
-
 pub struct CipherStream<S> {
     encrypt_state: CipherState<()>,
     decrypt_state: CipherState<Vec<u8>>,
@@ -92,7 +235,16 @@ impl<S> CipherStream<S> {
 }
 
 impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if let CipherState::Aead(state) = &mut this.encrypt_state {
+            return state.poll_read(&mut this.stream, cx, buf);
+        }
+
         let old_filled_size = buf.filled().len();
         ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
         let filled = &mut buf.filled_mut()[old_filled_size..];
@@ -104,8 +256,10 @@ impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
         match &mut self.encrypt_state {
             CipherState::Full(cipher, ..) => {
                 cipher.apply_keystream(filled);
-            },
-            CipherState::Partial { remaining, cipher, .. } => {
+            }
+            CipherState::Partial {
+                remaining, cipher, ..
+            } => {
                 let transform_size = remaining.get().min(filled.len());
                 cipher.apply_keystream(&mut filled[..transform_size]);
 
@@ -113,8 +267,8 @@ impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
                     Some(v) => *remaining = v,
                     None => self.encrypt_state = CipherState::None,
                 }
-            },
-            CipherState::None => {},
+            }
+            CipherState::None | CipherState::Aead(..) => {}
         }
 
         Poll::Ready(Ok(()))
@@ -122,21 +276,42 @@ impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
 }
 
 impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
-    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
         if buf.is_empty() {
             return Pin::new(&mut self.stream).poll_write(cx, buf);
         }
 
+        let this = &mut *self;
+        if let CipherState::Aead(state) = &mut this.decrypt_state {
+            ready!(state.poll_drain(&mut this.stream, cx))?;
+
+            // Once sealed the data counts as written, it goes out on the next write or flush
+            let n = buf.len().min(MAX_AEAD_CHUNK_LEN);
+            state.seal(&buf[..n])?;
+            let _ = state.poll_drain(&mut this.stream, cx)?;
+            return Poll::Ready(Ok(n));
+        }
+
         let mut decrypt_state = std::mem::take(&mut self.decrypt_state);
         let (cipher, enc_len, enc_buf) = match &mut decrypt_state {
             CipherState::Full(cipher, b) => (cipher, buf.len(), b),
-            CipherState::Partial { remaining, cipher, buffer } => (cipher, remaining.get().min(buf.len()), buffer),
+            CipherState::Partial {
+                remaining,
+                cipher,
+                buffer,
+            } => (cipher, remaining.get().min(buf.len()), buffer),
             CipherState::None => return Pin::new(&mut self.stream).poll_write(cx, buf),
+            CipherState::Aead(..) => unreachable!(),
         };
 
         let enc_len = enc_len.min(enc_buf.len());
-        cipher.apply_keystream_b2b(&buf[..enc_len], &mut enc_buf[..enc_len])
-            .map_err(|e| Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        cipher
+            .apply_keystream_b2b(&buf[..enc_len], &mut enc_buf[..enc_len])
+            .map_err(|e| Error::other(e.to_string()))?;
 
         let ret = Pin::new(&mut self.stream).poll_write(cx, &enc_buf[..enc_len]);
 
@@ -148,16 +323,23 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
 
         if byte_written < enc_len {
             // We only wrote part of the encrypted data, need to wind back the cipher
-            cipher.try_seek(cipher.current_pos::<u64>() - (enc_len - byte_written) as u64)
-                .map_err(|e| Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            cipher
+                .try_seek(cipher.current_pos::<u64>() - (enc_len - byte_written) as u64)
+                .map_err(|e| Error::other(e.to_string()))?;
         }
 
         self.decrypt_state = match decrypt_state {
-            CipherState::Partial { remaining, cipher, buffer } => {
-                match NonZeroUsize::new(remaining.get() - byte_written) {
-                    Some(v) => CipherState::Partial { remaining: v, cipher, buffer },
-                    None => CipherState::None,
-                }
+            CipherState::Partial {
+                remaining,
+                cipher,
+                buffer,
+            } => match NonZeroUsize::new(remaining.get() - byte_written) {
+                Some(v) => CipherState::Partial {
+                    remaining: v,
+                    cipher,
+                    buffer,
+                },
+                None => CipherState::None,
             },
             v => v,
         };
@@ -166,11 +348,19 @@ impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        Pin::new(&mut self.stream).poll_flush(cx)
+        let this = &mut *self;
+        if let CipherState::Aead(state) = &mut this.decrypt_state {
+            ready!(state.poll_drain(&mut this.stream, cx))?;
+        }
+        Pin::new(&mut this.stream).poll_flush(cx)
     }
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        Pin::new(&mut self.stream).poll_shutdown(cx)
+        let this = &mut *self;
+        if let CipherState::Aead(state) = &mut this.decrypt_state {
+            ready!(state.poll_drain(&mut this.stream, cx))?;
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
     }
 }
 
@@ -184,7 +374,7 @@ mod tests {
         client_send_config: Configuration,
         server_send_config: Configuration,
     ) {
-        let (client, server) =  tokio::io::duplex(32);
+        let (client, server) = tokio::io::duplex(32);
         let mut client_stream = CipherStream::new(client, &client_send_config, &server_send_config);
         let mut server_stream = CipherStream::new(server, &server_send_config, &client_send_config);
 
@@ -198,9 +388,15 @@ mod tests {
 
         println!("Test case: {}", name);
         client_stream.write_all(test_data).await.unwrap();
+        client_stream.flush().await.unwrap();
 
         let received_data = received_data.await.unwrap();
-        assert_eq!(test_data, &received_data[..], "Data mismatch in test case: {}", name);
+        assert_eq!(
+            test_data,
+            &received_data[..],
+            "Data mismatch in test case: {}",
+            name
+        );
     }
 
     #[tokio::test]
@@ -209,18 +405,58 @@ mod tests {
             "Plaintext to Partial",
             Configuration::Plaintext,
             Configuration::random_partial(NonZeroUsize::new(4).unwrap()),
-        ).await;
+        )
+        .await;
 
         test_case(
             "Plaintext to Full Encrypt",
             Configuration::Plaintext,
             Configuration::random_full(),
-        ).await;
+        )
+        .await;
 
         test_case(
             "Partial to Partial",
             Configuration::random_partial(NonZeroUsize::new(128).unwrap()),
             Configuration::random_partial(NonZeroUsize::new(16).unwrap()),
-        ).await;
+        )
+        .await;
+
+        test_case(
+            "AEAD to AEAD",
+            Configuration::random_aead(),
+            Configuration::random_aead(),
+        )
+        .await;
+
+        test_case(
+            "Full to AEAD",
+            Configuration::random_full(),
+            Configuration::random_aead(),
+        )
+        .await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_aead_tampering_is_detected() {
+        let config = Configuration::random_aead();
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client_stream = CipherStream::new(client, &Configuration::Plaintext, &config);
+        client_stream.write_all(b"Hello, World!").await.unwrap();
+        client_stream.flush().await.unwrap();
+
+        let mut sealed = vec![0u8; AEAD_LEN_SIZE + AEAD_TAG_LEN + 13 + AEAD_TAG_LEN];
+        server.read_exact(&mut sealed).await.unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+
+        let (mut tampered, tampered_server) = tokio::io::duplex(1024);
+        tampered.write_all(&sealed).await.unwrap();
+        let mut server_stream =
+            CipherStream::new(tampered_server, &config, &Configuration::Plaintext);
+
+        let mut buf = vec![0u8; 13];
+        let err = server_stream.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,10 +1,24 @@
 use anyhow::{Context, ensure};
-use std::cmp::Ordering;
 use std::io::Write;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for super::GeoIPv4Entry {}
+    impl Sealed for super::GeoIPv6Entry {}
+}
+
+/// An entry of a sorted GeoIP table, which is serialized as its in-memory bytes.
+pub trait GeoIPEntry: sealed::Sealed + Ord + Sized {
+    type Addr: Ord;
+
+    fn from(&self) -> Self::Addr;
+    fn to(&self) -> Self::Addr;
+    fn country_code(&self) -> &[u8; 2];
+}
 
 #[repr(C)]
-#[derive(Debug, Clone, Eq, PartialEq, Ord)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
 pub struct GeoIPv4Entry {
     from: [u8; 4],
     to: [u8; 4],
@@ -12,15 +26,43 @@ pub struct GeoIPv4Entry {
 }
 
 impl GeoIPv4Entry {
-    pub fn from(&self) -> Ipv4Addr {
+    pub fn new(from: Ipv4Addr, to: Ipv4Addr, country_code: [u8; 2]) -> Self {
+        assert!(to >= from, "Invalid IP range");
+
+        Self {
+            from: from.octets(),
+            to: to.octets(),
+            country_code,
+        }
+    }
+}
+
+impl GeoIPEntry for GeoIPv4Entry {
+    type Addr = Ipv4Addr;
+
+    fn from(&self) -> Ipv4Addr {
         self.from.into()
     }
 
-    pub fn to(&self) -> Ipv4Addr {
+    fn to(&self) -> Ipv4Addr {
         self.to.into()
     }
 
-    pub fn new(from: Ipv4Addr, to: Ipv4Addr, country_code: [u8; 2]) -> Self {
+    fn country_code(&self) -> &[u8; 2] {
+        &self.country_code
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct GeoIPv6Entry {
+    from: [u8; 16],
+    to: [u8; 16],
+    country_code: [u8; 2],
+}
+
+impl GeoIPv6Entry {
+    pub fn new(from: Ipv6Addr, to: Ipv6Addr, country_code: [u8; 2]) -> Self {
         assert!(to >= from, "Invalid IP range");
 
         Self {
@@ -31,35 +73,42 @@ impl GeoIPv4Entry {
     }
 }
 
-impl PartialOrd for GeoIPv4Entry {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(
-            self.from()
-                .cmp(&other.from())
-                .then_with(|| self.to().cmp(&other.to())),
-        )
+impl GeoIPEntry for GeoIPv6Entry {
+    type Addr = Ipv6Addr;
+
+    fn from(&self) -> Ipv6Addr {
+        self.from.into()
+    }
+
+    fn to(&self) -> Ipv6Addr {
+        self.to.into()
+    }
+
+    fn country_code(&self) -> &[u8; 2] {
+        &self.country_code
     }
 }
 
-pub fn find_country_code_v4<'a>(
-    ip: &Ipv4Addr,
+fn find_country_code<'a, E: GeoIPEntry + 'a>(
+    ip: &E::Addr,
     sorted_serialized_data: &'a [u8],
 ) -> anyhow::Result<Option<&'a str>> {
-    let entry_size = size_of::<GeoIPv4Entry>();
+    let entry_size = size_of::<E>();
     ensure!(
-        sorted_serialized_data.len() % entry_size == 0,
+        sorted_serialized_data.len().is_multiple_of(entry_size),
         "Invalid serialized data length, must be multiple of {entry_size}"
     );
 
+    // The entries are made of byte arrays only, so any bytes will do
     let entries = unsafe {
         std::slice::from_raw_parts(
-            sorted_serialized_data.as_ptr() as *const GeoIPv4Entry,
+            sorted_serialized_data.as_ptr() as *const E,
             sorted_serialized_data.len() / entry_size,
         )
     };
 
     let code = match entries.binary_search_by(|entry| entry.from().cmp(ip)) {
-        Ok(index) => &entries[index].country_code,
+        Ok(index) => entries[index].country_code(),
         Err(index) => {
             if index == 0 {
                 return Ok(None);
@@ -67,7 +116,7 @@ pub fn find_country_code_v4<'a>(
 
             let entry = &entries[index - 1];
             if ip <= &entry.to() {
-                &entry.country_code
+                entry.country_code()
             } else {
                 return Ok(None);
             }
@@ -79,13 +128,44 @@ pub fn find_country_code_v4<'a>(
         .map(Some)
 }
 
-pub fn serialize_entries(writer: impl Write, mut entries: Vec<GeoIPv4Entry>) -> anyhow::Result<()> {
+pub fn find_country_code_v4<'a>(
+    ip: &Ipv4Addr,
+    sorted_serialized_data: &'a [u8],
+) -> anyhow::Result<Option<&'a str>> {
+    find_country_code::<GeoIPv4Entry>(ip, sorted_serialized_data)
+}
+
+pub fn find_country_code_v6<'a>(
+    ip: &Ipv6Addr,
+    sorted_serialized_data: &'a [u8],
+) -> anyhow::Result<Option<&'a str>> {
+    find_country_code::<GeoIPv6Entry>(ip, sorted_serialized_data)
+}
+
+/// Looks up either kind of address in the matching table. IPv4-mapped IPv6 addresses are
+/// looked up as IPv4.
+pub fn find_country_code_ip<'a>(
+    ip: &IpAddr,
+    v4_data: &'a [u8],
+    v6_data: &'a [u8],
+) -> anyhow::Result<Option<&'a str>> {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => find_country_code_v4(&ip, v4_data),
+        IpAddr::V6(ip) => find_country_code_v6(&ip, v6_data),
+    }
+}
+
+pub fn serialize_entries<E: GeoIPEntry>(
+    writer: impl Write,
+    mut entries: Vec<E>,
+) -> anyhow::Result<()> {
     entries.sort();
 
     let mut writer = writer;
     for entry in entries {
-        let bytes: [u8; 10] = unsafe { std::mem::transmute(entry) };
-        writer.write_all(&bytes)?;
+        let bytes =
+            unsafe { std::slice::from_raw_parts(&entry as *const E as *const u8, size_of::<E>()) };
+        writer.write_all(bytes)?;
     }
 
     Ok(())
@@ -115,4 +195,30 @@ mod tests {
             Some("NZ")
         );
     }
+
+    #[test]
+    fn v6_serialization_and_mapping_works() {
+        let net = |s: &str| s.parse::<Ipv6Addr>().unwrap();
+        let entries = vec![
+            GeoIPv6Entry::new(net("2400:da00::"), net("2400:da00::ffff"), *b"CN"),
+            GeoIPv6Entry::new(net("2001:db8::"), net("2001:db8::ffff"), *b"NZ"),
+        ];
+
+        let mut serialized = vec![0u8; 0];
+        serialize_entries(&mut serialized, entries).expect("Failed to serialize entries");
+
+        assert_eq!(
+            find_country_code_v6(&net("2400:da00::1"), &serialized).expect("Lookup failed"),
+            Some("CN")
+        );
+        assert_eq!(
+            find_country_code_v6(&net("2001:db8::1:0"), &serialized).expect("Lookup failed"),
+            None
+        );
+        assert_eq!(
+            find_country_code_ip(&"2001:db8::2".parse().unwrap(), &[], &serialized)
+                .expect("Lookup failed"),
+            Some("NZ")
+        );
+    }
 }
@@ -0,0 +1,1005 @@
+use crate::hpack::{self, Decoder, Headers};
+use anyhow::{Context, bail, ensure};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Semaphore, mpsc, oneshot};
+
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const ERROR_NO_ERROR: u32 = 0x0;
+const ERROR_PROTOCOL: u32 = 0x1;
+const ERROR_FLOW_CONTROL: u32 = 0x3;
+const ERROR_CANCEL: u32 = 0x8;
+const ERROR_COMPRESSION: u32 = 0x9;
+const ERROR_ENHANCE_YOUR_CALM: u32 = 0xb;
+
+// Length (3 bytes), type, flags, stream id (4 bytes)
+const HEADER_LEN: usize = 9;
+// The smallest frame size a peer may allow, which we never go over
+const MAX_FRAME_LEN: usize = 16 * 1024;
+const MAX_HEADER_BLOCK_LEN: usize = 256 * 1024;
+const DEFAULT_WINDOW: usize = 65_535;
+const LOCAL_WINDOW: usize = 1024 * 1024;
+const MAX_STREAM_ID: u32 = 0x7FFF_FFFF;
+// DATA frames queued for a stream whose reader has fallen behind. The window already bounds
+// the bytes, this bounds a peer cutting them into tiny frames.
+const MAX_QUEUED_FRAMES: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone)]
+enum Frame {
+    Headers {
+        id: u32,
+        block: Vec<u8>,
+        end_stream: bool,
+    },
+    Data {
+        id: u32,
+        data: Bytes,
+        end_stream: bool,
+    },
+    WindowUpdate {
+        id: u32,
+        increment: u32,
+    },
+    Reset {
+        id: u32,
+        code: u32,
+    },
+    Settings {
+        ack: bool,
+        settings: Vec<(u16, u32)>,
+    },
+    Ping {
+        ack: bool,
+        data: [u8; 8],
+    },
+    GoAway {
+        last_id: u32,
+        code: u32,
+    },
+}
+
+/// An error that ends the whole connection, with the code to send the peer in a GOAWAY.
+#[derive(Debug)]
+struct ConnectionError(u32);
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP/2 connection error {:#x}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+fn encode_frame(out: &mut Vec<u8>, frame_type: u8, flags: u8, id: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(frame_type);
+    out.push(flags);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+impl Frame {
+    fn encode(self, out: &mut Vec<u8>) {
+        match self {
+            Frame::Headers {
+                id,
+                block,
+                end_stream,
+            } => {
+                // Anything over a frame goes in CONTINUATION frames right after
+                let mut chunks = block.chunks(MAX_FRAME_LEN).peekable();
+                let mut frame_type = FRAME_HEADERS;
+                let mut flags = if end_stream { FLAG_END_STREAM } else { 0 };
+                while let Some(chunk) = chunks.next() {
+                    if chunks.peek().is_none() {
+                        flags |= FLAG_END_HEADERS;
+                    }
+                    encode_frame(out, frame_type, flags, id, chunk);
+                    frame_type = FRAME_CONTINUATION;
+                    flags = 0;
+                }
+                if block.is_empty() {
+                    encode_frame(out, frame_type, flags | FLAG_END_HEADERS, id, &[]);
+                }
+            }
+            Frame::Data {
+                id,
+                data,
+                end_stream,
+            } => {
+                let flags = if end_stream { FLAG_END_STREAM } else { 0 };
+                encode_frame(out, FRAME_DATA, flags, id, &data);
+            }
+            Frame::WindowUpdate { id, increment } => {
+                encode_frame(out, FRAME_WINDOW_UPDATE, 0, id, &increment.to_be_bytes());
+            }
+            Frame::Reset { id, code } => {
+                encode_frame(out, FRAME_RST_STREAM, 0, id, &code.to_be_bytes());
+            }
+            Frame::Settings { ack, settings } => {
+                let payload: Vec<u8> = settings
+                    .iter()
+                    .flat_map(|(id, value)| {
+                        [id.to_be_bytes().as_slice(), &value.to_be_bytes()].concat()
+                    })
+                    .collect();
+                let flags = if ack { FLAG_ACK } else { 0 };
+                encode_frame(out, FRAME_SETTINGS, flags, 0, &payload);
+            }
+            Frame::Ping { ack, data } => {
+                let flags = if ack { FLAG_ACK } else { 0 };
+                encode_frame(out, FRAME_PING, flags, 0, &data);
+            }
+            Frame::GoAway { last_id, code } => {
+                let payload = [last_id.to_be_bytes(), code.to_be_bytes()].concat();
+                encode_frame(out, FRAME_GOAWAY, 0, 0, &payload);
+            }
+        }
+    }
+}
+
+struct RawFrame {
+    frame_type: u8,
+    flags: u8,
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl RawFrame {
+    async fn read(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Self>> {
+        let mut header = [0u8; HEADER_LEN];
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Error reading HTTP/2 frame header"),
+        }
+
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        ensure!(len <= MAX_FRAME_LEN, "HTTP/2 frame too large: {len} bytes");
+
+        let mut payload = vec![0u8; len];
+        stream
+            .read_exact(&mut payload)
+            .await
+            .context("Error reading HTTP/2 frame payload")?;
+
+        Ok(Some(Self {
+            frame_type: header[3],
+            flags: header[4],
+            id: u32::from_be_bytes(header[5..9].try_into().unwrap()) & MAX_STREAM_ID,
+            payload,
+        }))
+    }
+
+    /// The payload of a DATA or HEADERS frame, without padding and priority fields.
+    fn content(&self) -> anyhow::Result<&[u8]> {
+        let mut payload = self.payload.as_slice();
+        let mut padding = 0;
+        if self.flags & FLAG_PADDED != 0 {
+            let (&len, rest) = payload.split_first().context("Missing HTTP/2 pad length")?;
+            padding = len as usize;
+            payload = rest;
+        }
+        if self.frame_type == FRAME_HEADERS && self.flags & FLAG_PRIORITY != 0 {
+            ensure!(payload.len() >= 5, "Truncated HTTP/2 priority");
+            payload = &payload[5..];
+        }
+        ensure!(payload.len() >= padding, "Invalid HTTP/2 padding");
+        Ok(&payload[..payload.len() - padding])
+    }
+
+    fn u32_at(&self, offset: usize) -> anyhow::Result<u32> {
+        self.payload
+            .get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+            .context("Truncated HTTP/2 frame")
+    }
+}
+
+struct StreamEntry {
+    // None once the peer has ended its side of the stream
+    data_tx: Option<mpsc::Sender<Bytes>>,
+    credit: Arc<Semaphore>,
+    // How much more the peer may send before we update the window
+    recv_window: usize,
+    local_closed: bool,
+}
+
+struct State {
+    closed: bool,
+    streams: HashMap<u32, StreamEntry>,
+    pending_responses: HashMap<u32, oneshot::Sender<Headers>>,
+    peer_initial_window: usize,
+    conn_recv_window: usize,
+}
+
+struct Shared {
+    frames_tx: mpsc::Sender<Frame>,
+    state: Mutex<State>,
+    next_id: AtomicU32,
+    conn_credit: Arc<Semaphore>,
+}
+
+impl Shared {
+    /// Sets up a stream and sends its headers, so any DATA the peer sends in return has
+    /// somewhere to go.
+    async fn attach(
+        self: &Arc<Self>,
+        id: u32,
+        block: Vec<u8>,
+        response_tx: Option<oneshot::Sender<Headers>>,
+    ) -> anyhow::Result<DuplexStream> {
+        let (app, pump) = tokio::io::duplex(MAX_FRAME_LEN * 4);
+        let (pump_r, pump_w) = tokio::io::split(pump);
+        let (data_tx, data_rx) = mpsc::channel(MAX_QUEUED_FRAMES);
+
+        let credit = {
+            let mut state = self.state.lock().unwrap();
+            ensure!(!state.closed, "HTTP/2 connection closed");
+
+            let credit = Arc::new(Semaphore::new(state.peer_initial_window));
+            state.streams.insert(
+                id,
+                StreamEntry {
+                    data_tx: Some(data_tx),
+                    credit: credit.clone(),
+                    recv_window: LOCAL_WINDOW,
+                    local_closed: false,
+                },
+            );
+
+            if let Some(tx) = response_tx {
+                state.pending_responses.insert(id, tx);
+            }
+            credit
+        };
+
+        // The pumps aren't running yet, so no DATA can go out before the headers
+        let headers = Frame::Headers {
+            id,
+            block,
+            end_stream: false,
+        };
+        if self.frames_tx.send(headers).await.is_err() {
+            self.remove_stream(id);
+            bail!("HTTP/2 connection closed");
+        }
+
+        tokio::spawn(pump_outbound(id, pump_r, credit, self.clone()));
+        tokio::spawn(pump_inbound(id, pump_w, data_rx, self.clone()));
+        Ok(app)
+    }
+
+    fn remove_stream(&self, id: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.pending_responses.remove(&id);
+        if let Some(entry) = state.streams.remove(&id) {
+            entry.credit.close();
+        }
+    }
+
+    /// Gives received bytes back to the connection's window, and the stream's if given.
+    /// Returns false once the connection is gone.
+    async fn release_window(&self, id: Option<u32>, len: usize) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.conn_recv_window += len;
+            if let Some(entry) = id.and_then(|id| state.streams.get_mut(&id)) {
+                entry.recv_window += len;
+            }
+        }
+
+        for id in id.into_iter().chain([0]) {
+            let increment = len as u32;
+            let frame = Frame::WindowUpdate { id, increment };
+            if self.frames_tx.send(frame).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn reset_stream(&self, id: u32, code: u32) {
+        self.remove_stream(id);
+        let _ = self.frames_tx.send(Frame::Reset { id, code }).await;
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.pending_responses.clear();
+        for (_, entry) in state.streams.drain() {
+            entry.credit.close();
+        }
+        self.conn_credit.close();
+    }
+}
+
+async fn pump_outbound(
+    id: u32,
+    mut stream: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    shared: Arc<Shared>,
+) {
+    let mut buf = vec![0u8; MAX_FRAME_LEN];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+
+        // DATA counts against both the stream's window and the connection's
+        let Ok(permit) = credit.acquire_many(n as u32).await else {
+            return;
+        };
+        permit.forget();
+        let Ok(permit) = shared.conn_credit.acquire_many(n as u32).await else {
+            return;
+        };
+        permit.forget();
+
+        let data = Bytes::copy_from_slice(&buf[..n]);
+        let frame = Frame::Data {
+            id,
+            data,
+            end_stream: false,
+        };
+        if shared.frames_tx.send(frame).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = shared
+        .frames_tx
+        .send(Frame::Data {
+            id,
+            data: Bytes::new(),
+            end_stream: true,
+        })
+        .await;
+
+    let mut state = shared.state.lock().unwrap();
+    if let Some(entry) = state.streams.get_mut(&id) {
+        entry.local_closed = true;
+        if entry.data_tx.is_none() {
+            state.streams.remove(&id);
+        }
+    }
+}
+
+async fn pump_inbound(
+    id: u32,
+    mut stream: WriteHalf<DuplexStream>,
+    mut data_rx: mpsc::Receiver<Bytes>,
+    shared: Arc<Shared>,
+) {
+    while let Some(data) = data_rx.recv().await {
+        if stream.write_all(&data).await.is_err() {
+            // Nobody is reading this stream anymore, tell the peer to stop sending
+            shared.reset_stream(id, ERROR_CANCEL).await;
+            return;
+        }
+
+        if data.is_empty() {
+            continue;
+        }
+
+        if !shared.release_window(Some(id), data.len()).await {
+            return;
+        }
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+/// Marks the peer's side of a stream as ended.
+fn end_remote(shared: &Shared, id: u32) {
+    let mut state = shared.state.lock().unwrap();
+    if let Some(entry) = state.streams.get_mut(&id) {
+        entry.data_tx = None;
+        if entry.local_closed {
+            state.streams.remove(&id);
+        }
+    }
+}
+
+async fn read_loop(
+    mut stream: impl AsyncRead + Unpin,
+    shared: &Arc<Shared>,
+    incoming_tx: Option<mpsc::Sender<IncomingRequest>>,
+    last_incoming_id: &mut u32,
+) -> anyhow::Result<()> {
+    let mut decoder = Decoder::default();
+
+    while let Some(frame) = RawFrame::read(&mut stream).await? {
+        match frame.frame_type {
+            FRAME_HEADERS => {
+                let id = frame.id;
+                let end_stream = frame.flags & FLAG_END_STREAM != 0;
+                let mut block = frame.content()?.to_vec();
+
+                // The rest of the block must follow right away
+                let mut end_headers = frame.flags & FLAG_END_HEADERS != 0;
+                while !end_headers {
+                    let frame = RawFrame::read(&mut stream)
+                        .await?
+                        .context("HTTP/2 connection closed within a header block")?;
+                    ensure!(
+                        frame.frame_type == FRAME_CONTINUATION && frame.id == id,
+                        "Expected a CONTINUATION frame"
+                    );
+                    ensure!(
+                        block.len() + frame.payload.len() <= MAX_HEADER_BLOCK_LEN,
+                        "HTTP/2 header block too large"
+                    );
+                    block.extend_from_slice(&frame.payload);
+                    end_headers = frame.flags & FLAG_END_HEADERS != 0;
+                }
+
+                // The decoder's table is out of step with the peer's now, so nothing after
+                // this can be decoded
+                let headers = decoder
+                    .decode(&block)
+                    .context(ConnectionError(ERROR_COMPRESSION))?;
+
+                let response_tx = shared.state.lock().unwrap().pending_responses.remove(&id);
+                if let Some(tx) = response_tx {
+                    let _ = tx.send(headers);
+                } else if let Some(incoming_tx) = incoming_tx.as_ref()
+                    && id % 2 == 1
+                    && id > *last_incoming_id
+                {
+                    *last_incoming_id = id;
+                    let incoming = IncomingRequest {
+                        headers,
+                        id,
+                        shared: shared.clone(),
+                    };
+                    if incoming_tx.send(incoming).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+
+                // Trailers, or a response with no body
+                if end_stream {
+                    end_remote(shared, id);
+                }
+            }
+
+            FRAME_DATA => {
+                let content = frame.content()?;
+                let data = Bytes::copy_from_slice(content);
+                let len = frame.payload.len();
+                let padding = len - content.len();
+
+                let (delivered, reset) = {
+                    let mut state = shared.state.lock().unwrap();
+                    if len > state.conn_recv_window {
+                        return Err(ConnectionError(ERROR_FLOW_CONTROL))
+                            .context("HTTP/2 connection window exceeded");
+                    }
+                    state.conn_recv_window -= len;
+
+                    match state.streams.get_mut(&frame.id) {
+                        Some(entry) if entry.data_tx.is_some() => {
+                            if len > entry.recv_window {
+                                (false, Some(ERROR_FLOW_CONTROL))
+                            } else {
+                                entry.recv_window -= len;
+                                match entry.data_tx.as_ref().unwrap().try_send(data) {
+                                    Ok(()) => (true, None),
+                                    Err(TrySendError::Full(_)) => {
+                                        (false, Some(ERROR_ENHANCE_YOUR_CALM))
+                                    }
+                                    Err(TrySendError::Closed(_)) => (false, None),
+                                }
+                            }
+                        }
+                        _ => (false, None),
+                    }
+                };
+
+                if let Some(code) = reset {
+                    shared.reset_stream(frame.id, code).await;
+                }
+
+                // Padding and data nobody will read still count against the windows
+                let undelivered = if delivered { padding } else { len };
+                if undelivered > 0 {
+                    let id = delivered.then_some(frame.id);
+                    shared.release_window(id, undelivered).await;
+                }
+
+                if frame.flags & FLAG_END_STREAM != 0 {
+                    end_remote(shared, frame.id);
+                }
+            }
+
+            FRAME_WINDOW_UPDATE => {
+                let increment = (frame.u32_at(0)? & MAX_STREAM_ID) as usize;
+                if frame.id == 0 {
+                    shared.conn_credit.add_permits(increment);
+                } else if let Some(entry) = shared.state.lock().unwrap().streams.get(&frame.id) {
+                    entry.credit.add_permits(increment);
+                }
+            }
+
+            FRAME_RST_STREAM => shared.remove_stream(frame.id),
+
+            FRAME_SETTINGS => {
+                if frame.flags & FLAG_ACK != 0 {
+                    continue;
+                }
+
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value = u32::from_be_bytes(setting[2..6].try_into().unwrap()) as usize;
+                    if id == SETTINGS_INITIAL_WINDOW_SIZE {
+                        let mut state = shared.state.lock().unwrap();
+                        let old = std::mem::replace(&mut state.peer_initial_window, value);
+                        for entry in state.streams.values() {
+                            if value >= old {
+                                entry.credit.add_permits(value - old);
+                            } else {
+                                entry.credit.forget_permits(old - value);
+                            }
+                        }
+                    }
+                }
+
+                let _ = shared
+                    .frames_tx
+                    .send(Frame::Settings {
+                        ack: true,
+                        settings: vec![],
+                    })
+                    .await;
+            }
+
+            FRAME_PING if frame.flags & FLAG_ACK == 0 => {
+                let data = frame
+                    .payload
+                    .as_slice()
+                    .try_into()
+                    .context("Invalid HTTP/2 ping")?;
+                let _ = shared.frames_tx.send(Frame::Ping { ack: true, data }).await;
+            }
+
+            FRAME_GOAWAY => break,
+
+            FRAME_CONTINUATION => bail!("Unexpected CONTINUATION frame"),
+
+            // Priority, push promises and extensions, none of which matter here
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_loop(
+    mut stream: impl AsyncWrite + Unpin,
+    mut frames_rx: mpsc::Receiver<Frame>,
+    is_client: bool,
+) -> anyhow::Result<()> {
+    let mut buf = vec![];
+    if is_client {
+        buf.extend_from_slice(PREFACE);
+    }
+
+    while let Some(frame) = frames_rx.recv().await {
+        let go_away = matches!(frame, Frame::GoAway { .. });
+        frame.encode(&mut buf);
+        stream
+            .write_all(&buf)
+            .await
+            .context("Error writing HTTP/2 frame")?;
+        buf.clear();
+
+        if go_away {
+            stream
+                .flush()
+                .await
+                .context("Error flushing HTTP/2 frames")?;
+            break;
+        }
+
+        if frames_rx.is_empty() {
+            stream
+                .flush()
+                .await
+                .context("Error flushing HTTP/2 frames")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A minimal HTTP/2 connection, carrying a byte stream in each request and response body.
+/// It speaks just enough of the protocol to pass as a regular HTTP/2 client or server.
+#[derive(Clone)]
+pub struct H2Session {
+    shared: Arc<Shared>,
+}
+
+impl H2Session {
+    async fn start<S>(
+        stream: S,
+        incoming_tx: Option<mpsc::Sender<IncomingRequest>>,
+    ) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let is_client = incoming_tx.is_none();
+        let (mut r, w) = tokio::io::split(stream);
+
+        if !is_client {
+            let mut preface = [0u8; PREFACE.len()];
+            r.read_exact(&mut preface)
+                .await
+                .context("Error reading HTTP/2 preface")?;
+            ensure!(preface == PREFACE, "Invalid HTTP/2 preface");
+        }
+
+        let (frames_tx, frames_rx) = mpsc::channel(64);
+        let mut settings = vec![
+            (SETTINGS_INITIAL_WINDOW_SIZE, LOCAL_WINDOW as u32),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                hpack::MAX_HEADER_LIST_SIZE as u32,
+            ),
+        ];
+        if is_client {
+            settings.push((SETTINGS_ENABLE_PUSH, 0));
+        }
+        frames_tx
+            .send(Frame::Settings {
+                ack: false,
+                settings,
+            })
+            .await?;
+        frames_tx
+            .send(Frame::WindowUpdate {
+                id: 0,
+                increment: (LOCAL_WINDOW - DEFAULT_WINDOW) as u32,
+            })
+            .await?;
+
+        let shared = Arc::new(Shared {
+            frames_tx,
+            state: Mutex::new(State {
+                closed: false,
+                streams: Default::default(),
+                pending_responses: Default::default(),
+                peer_initial_window: DEFAULT_WINDOW,
+                // Our WINDOW_UPDATE goes out before anything else we send
+                conn_recv_window: LOCAL_WINDOW,
+            }),
+            next_id: AtomicU32::new(1),
+            conn_credit: Arc::new(Semaphore::new(DEFAULT_WINDOW)),
+        });
+
+        let driver_shared = shared.clone();
+        let incoming_closed = incoming_tx.clone();
+        tokio::spawn(async move {
+            let _ = tokio::select! {
+                r = async {
+                    let mut last_id = 0;
+                    let result = read_loop(r, &driver_shared, incoming_tx, &mut last_id).await;
+                    if let Err(e) = &result {
+                        let code = e.downcast_ref::<ConnectionError>().map_or(ERROR_PROTOCOL, |e| e.0);
+                        let frame = Frame::GoAway { last_id, code };
+                        if driver_shared.frames_tx.try_send(frame).is_ok() {
+                            // The write loop ends the connection once the GOAWAY is out
+                            std::future::pending::<()>().await;
+                        }
+                    }
+                    result
+                } => r,
+                r = write_loop(w, frames_rx, is_client) => r,
+                _ = async {
+                    match &incoming_closed {
+                        Some(tx) => tx.closed().await,
+                        None => std::future::pending().await,
+                    }
+                } => Ok(()),
+            };
+            driver_shared.close();
+        });
+
+        Ok(Self { shared })
+    }
+
+    /// Starts the client side of a connection.
+    pub async fn client<S>(stream: S) -> anyhow::Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::start(stream, None).await
+    }
+
+    /// Starts the server side of a connection whose preface hasn't been read yet, yielding
+    /// every request the client sends. Dropping the receiver closes the connection.
+    pub async fn server<S>(stream: S) -> anyhow::Result<mpsc::Receiver<IncomingRequest>>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        Self::start(stream, Some(incoming_tx)).await?;
+        Ok(incoming_rx)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    /// Sends a request and waits for the response headers. The body goes both ways over the
+    /// returned stream.
+    pub async fn open(&self, headers: &Headers) -> anyhow::Result<(Headers, DuplexStream)> {
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        if id > MAX_STREAM_ID {
+            // Out of stream ids, the caller will have to start over on a new connection
+            self.shared.close();
+            bail!("HTTP/2 connection ran out of stream ids");
+        }
+
+        let (response_tx, response_rx) = oneshot::channel();
+        let stream = self
+            .shared
+            .attach(id, hpack::encode(headers), Some(response_tx))
+            .await?;
+
+        let headers = response_rx
+            .await
+            .context("HTTP/2 stream closed before the response")?;
+        Ok((headers, stream))
+    }
+}
+
+/// A request the client has sent, waiting for the server to respond.
+pub struct IncomingRequest {
+    pub headers: Headers,
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl IncomingRequest {
+    /// Sends the response headers, with the body carried by the returned stream.
+    pub async fn respond(self, headers: &Headers) -> anyhow::Result<DuplexStream> {
+        self.shared
+            .attach(self.id, hpack::encode(headers), None)
+            .await
+    }
+
+    /// Sends a response with no body and drops the stream.
+    pub async fn respond_empty(self, headers: &Headers) -> anyhow::Result<()> {
+        self.shared
+            .frames_tx
+            .send(Frame::Headers {
+                id: self.id,
+                block: hpack::encode(headers),
+                end_stream: true,
+            })
+            .await
+            .context("HTTP/2 connection closed")?;
+        let _ = self
+            .shared
+            .frames_tx
+            .send(Frame::Reset {
+                id: self.id,
+                code: ERROR_NO_ERROR,
+            })
+            .await;
+        Ok(())
+    }
+}
+
+/// Looks up a header, pseudo-headers included.
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn h2_session_works() {
+        let (client, server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            let mut incoming = H2Session::server(server).await.unwrap();
+            while let Some(request) = incoming.recv().await {
+                tokio::spawn(async move {
+                    let path = header(&request.headers, ":path").unwrap().to_string();
+                    if path == "/missing" {
+                        request
+                            .respond_empty(&vec![(":status".into(), "404".into())])
+                            .await
+                            .unwrap();
+                        return;
+                    }
+
+                    let (mut r, mut w) = tokio::io::split(
+                        request
+                            .respond(&vec![(":status".into(), "200".into())])
+                            .await
+                            .unwrap(),
+                    );
+                    tokio::io::copy(&mut r, &mut w).await.unwrap();
+                    w.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        let session = H2Session::client(client).await.unwrap();
+        let request = |path: &str| -> Headers {
+            vec![
+                (":method".into(), "POST".into()),
+                (":scheme".into(), "https".into()),
+                (":authority".into(), "example.com".into()),
+                (":path".into(), path.into()),
+                ("authorization".into(), "x".repeat(MAX_FRAME_LEN * 2)),
+            ]
+        };
+
+        let (headers, _) = session.open(&request("/missing")).await.unwrap();
+        assert_eq!(header(&headers, ":status"), Some("404"));
+
+        // More than the default windows over several concurrent streams
+        let data: Vec<u8> = (0..DEFAULT_WINDOW * 3).map(|i| i as u8).collect();
+        let mut tasks = vec![];
+        for _ in 0..3 {
+            let session = session.clone();
+            let data = data.clone();
+            tasks.push(tokio::spawn(async move {
+                let (headers, stream) = session.open(&request("/echo")).await.unwrap();
+                assert_eq!(header(&headers, ":status"), Some("200"));
+
+                let (mut r, mut w) = tokio::io::split(stream);
+                let expected = data.clone();
+                let write = tokio::spawn(async move {
+                    w.write_all(&data).await.unwrap();
+                    w.shutdown().await.unwrap();
+                });
+                let mut received = vec![];
+                r.read_to_end(&mut received).await.unwrap();
+                write.await.unwrap();
+                assert_eq!(received, expected);
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+    }
+
+    /// Reads frames until a GOAWAY, returning its error code.
+    async fn read_go_away(stream: &mut (impl AsyncRead + Unpin)) -> u32 {
+        loop {
+            let frame = RawFrame::read(stream).await.unwrap().unwrap();
+            if frame.frame_type == FRAME_GOAWAY {
+                return frame.u32_at(4).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn h2_header_bomb_goes_away() {
+        let (mut client, server) = tokio::io::duplex(MAX_FRAME_LEN * 4);
+        let _incoming = tokio::spawn(H2Session::server(server));
+
+        // A large header, then a few bytes repeating it past the header list limit
+        let mut block = hpack::encode(&[("x-large".into(), "a".repeat(4000))]);
+        block[0] = 0x40;
+        block.extend(std::iter::repeat_n(0xbe, 100));
+
+        let mut out = PREFACE.to_vec();
+        Frame::Headers {
+            id: 1,
+            block,
+            end_stream: false,
+        }
+        .encode(&mut out);
+        client.write_all(&out).await.unwrap();
+
+        assert_eq!(read_go_away(&mut client).await, ERROR_COMPRESSION);
+    }
+
+    /// Opens stream 1 on a raw connection to a server that responds but never reads the body.
+    async fn open_unread_stream() -> (DuplexStream, DuplexStream) {
+        let (mut client, server) = tokio::io::duplex(LOCAL_WINDOW * 2);
+        let (body_tx, body_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let mut incoming = H2Session::server(server).await.unwrap();
+            let request = incoming.recv().await.unwrap();
+            let body = request
+                .respond(&vec![(":status".into(), "200".into())])
+                .await
+                .unwrap();
+            let _ = body_tx.send(body);
+            let _ = incoming.recv().await;
+        });
+
+        let mut out = PREFACE.to_vec();
+        Frame::Headers {
+            id: 1,
+            block: hpack::encode(&[(":method".into(), "POST".into())]),
+            end_stream: false,
+        }
+        .encode(&mut out);
+        client.write_all(&out).await.unwrap();
+        (client, body_rx.await.unwrap())
+    }
+
+    async fn send_data(client: &mut DuplexStream, frames: usize, len: usize) {
+        let mut out = vec![];
+        for _ in 0..frames {
+            Frame::Data {
+                id: 1,
+                data: vec![0; len].into(),
+                end_stream: false,
+            }
+            .encode(&mut out);
+        }
+        // The server may well hang up before taking it all
+        let _ = client.write_all(&out).await;
+    }
+
+    #[tokio::test]
+    async fn h2_enforces_receive_window() {
+        let (mut client, _body) = open_unread_stream().await;
+
+        // Twice the window, of which only what fits in the body's buffer gets read
+        send_data(&mut client, LOCAL_WINDOW * 2 / MAX_FRAME_LEN, MAX_FRAME_LEN).await;
+        assert_eq!(read_go_away(&mut client).await, ERROR_FLOW_CONTROL);
+    }
+
+    #[tokio::test]
+    async fn h2_resets_streams_flooded_with_frames() {
+        let (mut client, _body) = open_unread_stream().await;
+
+        // Well within the window, but in more frames than we queue
+        send_data(&mut client, MAX_QUEUED_FRAMES * 4, 64).await;
+        loop {
+            let frame = RawFrame::read(&mut client).await.unwrap().unwrap();
+            if frame.frame_type == FRAME_RST_STREAM {
+                assert_eq!(frame.id, 1);
+                assert_eq!(frame.u32_at(0).unwrap(), ERROR_ENHANCE_YOUR_CALM);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::encrypt_stream::Configuration;
+use anyhow::{ensure, format_err};
+use hkdf::Hkdf;
+use rand::random;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// One side of an ephemeral X25519 exchange. The stream ciphers are derived from the shared
+/// secret, so recorded streams stay safe even if the pre-shared key leaks later. The request
+/// and response that carry the exchange, initial data included, don't get that protection.
+pub struct EphemeralKey {
+    secret: StaticSecret,
+}
+
+impl EphemeralKey {
+    pub fn random() -> Self {
+        Self {
+            secret: StaticSecret::from(random::<[u8; 32]>()),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// Derives the stream ciphers on the client, given the server's public key.
+    pub fn client_derive(
+        self,
+        server_public: &[u8; 32],
+        client_send_cipher: &Configuration,
+        server_send_cipher: &Configuration,
+    ) -> anyhow::Result<(Configuration, Configuration)> {
+        let client_public = self.public_key();
+        self.derive(
+            server_public,
+            &client_public,
+            server_public,
+            client_send_cipher,
+            server_send_cipher,
+        )
+    }
+
+    /// Derives the stream ciphers on the server, given the client's public key.
+    pub fn server_derive(
+        self,
+        client_public: &[u8; 32],
+        client_send_cipher: &Configuration,
+        server_send_cipher: &Configuration,
+    ) -> anyhow::Result<(Configuration, Configuration)> {
+        let server_public = self.public_key();
+        self.derive(
+            client_public,
+            client_public,
+            &server_public,
+            client_send_cipher,
+            server_send_cipher,
+        )
+    }
+
+    fn derive(
+        self,
+        peer_public: &[u8; 32],
+        client_public: &[u8; 32],
+        server_public: &[u8; 32],
+        client_send_cipher: &Configuration,
+        server_send_cipher: &Configuration,
+    ) -> anyhow::Result<(Configuration, Configuration)> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_public));
+        ensure!(shared.was_contributory(), "Invalid ephemeral public key");
+
+        let salt = [client_public.as_slice(), server_public.as_slice()].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+
+        Ok((
+            rekey(&hkdf, b"cpxy-ng client send", client_send_cipher)?,
+            rekey(&hkdf, b"cpxy-ng server send", server_send_cipher)?,
+        ))
+    }
+}
+
+/// Replaces the key material of a configuration, keeping its kind and parameters.
+fn rekey(
+    hkdf: &Hkdf<Sha256>,
+    info: &[u8],
+    config: &Configuration,
+) -> anyhow::Result<Configuration> {
+    let mut okm = [0u8; 44];
+    hkdf.expand(info, &mut okm)
+        .map_err(|e| format_err!("Error deriving cipher key: {e}"))?;
+
+    let key: [u8; 32] = okm[..32].try_into().unwrap();
+    let nonce: [u8; 12] = okm[32..].try_into().unwrap();
+
+    Ok(match config {
+        Configuration::Plaintext => Configuration::Plaintext,
+        Configuration::PartialEncrypt { enc_size, .. } => Configuration::PartialEncrypt {
+            key,
+            nonce,
+            enc_size: *enc_size,
+        },
+        Configuration::FullEncrypt { .. } => Configuration::FullEncrypt { key, nonce },
+        Configuration::AeadEncrypt { .. } => Configuration::AeadEncrypt { key },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_sides_derive_the_same_ciphers() {
+        let client_send_cipher = Configuration::random_aead();
+        let server_send_cipher = Configuration::random_full();
+
+        let client = EphemeralKey::random();
+        let server = EphemeralKey::random();
+        let client_public = client.public_key();
+        let server_public = server.public_key();
+
+        let client_ciphers = client
+            .client_derive(&server_public, &client_send_cipher, &server_send_cipher)
+            .unwrap();
+        let server_ciphers = server
+            .server_derive(&client_public, &client_send_cipher, &server_send_cipher)
+            .unwrap();
+
+        assert_eq!(client_ciphers, server_ciphers);
+        assert_ne!(client_ciphers.0, client_send_cipher);
+        assert!(matches!(
+            client_ciphers.0,
+            Configuration::AeadEncrypt { .. }
+        ));
+        assert!(matches!(
+            client_ciphers.1,
+            Configuration::FullEncrypt { .. }
+        ));
+
+        assert!(
+            EphemeralKey::random()
+                .client_derive(&[0u8; 32], &client_send_cipher, &server_send_cipher)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,646 @@
+use anyhow::{Context, bail, ensure};
+use std::collections::VecDeque;
+use std::sync::LazyLock;
+
+pub type Headers = Vec<(String, String)>;
+
+// Every entry costs its name and value plus 32 bytes, as per RFC 7541
+const ENTRY_OVERHEAD: usize = 32;
+const DEFAULT_TABLE_SIZE: usize = 4096;
+/// The largest header list we decode, counted the same way as table entries. Without it, a
+/// small block repeating an indexed entry would expand into any amount of memory.
+pub const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+
+static STATIC_TABLE: &[(&str, &str)] = &[
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// The code and its length in bits for each byte, then EOS, from RFC 7541 appendix B
+static HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+fn encode_int(out: &mut Vec<u8>, first_byte: u8, prefix_bits: u8, mut value: usize) {
+    let max_prefix = (1usize << prefix_bits) - 1;
+    if value < max_prefix {
+        out.push(first_byte | value as u8);
+        return;
+    }
+
+    out.push(first_byte | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn encode_str(out: &mut Vec<u8>, s: &str) {
+    encode_int(out, 0, 7, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Encodes a header block without touching the peer's dynamic table, so the encoder needs
+/// no state. Strings are always sent as is, never Huffman coded.
+pub fn encode(headers: &[(String, String)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (name, value) in headers {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|(n, v)| n == name && v == value)
+        {
+            encode_int(&mut out, 0x80, 7, index + 1);
+            continue;
+        }
+
+        // Literal header field without indexing
+        match STATIC_TABLE.iter().position(|(n, _)| n == name) {
+            Some(index) => encode_int(&mut out, 0, 4, index + 1),
+            None => {
+                out.push(0);
+                encode_str(&mut out, name);
+            }
+        }
+        encode_str(&mut out, value);
+    }
+    out
+}
+
+/// Decodes header blocks, keeping the dynamic table across the blocks of a connection.
+pub struct Decoder {
+    table: VecDeque<(String, String)>,
+    table_size: usize,
+    max_table_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self {
+            table: Default::default(),
+            table_size: 0,
+            max_table_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    pub fn decode(&mut self, mut block: &[u8]) -> anyhow::Result<Headers> {
+        let mut headers = vec![];
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            let header = if first & 0x80 != 0 {
+                let index = decode_int(&mut block, 7)?;
+                self.entry(index)?
+            } else if first & 0x40 != 0 {
+                let header = self.decode_literal(&mut block, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0x20 != 0 {
+                let size = decode_int(&mut block, 5)?;
+                ensure!(
+                    size <= DEFAULT_TABLE_SIZE,
+                    "HPACK table size update too large"
+                );
+                self.max_table_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // Literal without indexing or never indexed, same thing to us
+                self.decode_literal(&mut block, 4)?
+            };
+
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            ensure!(
+                list_size <= MAX_HEADER_LIST_SIZE,
+                "HPACK header list too large"
+            );
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    fn entry(&self, index: usize) -> anyhow::Result<(String, String)> {
+        ensure!(index > 0, "Invalid HPACK index 0");
+        if let Some((name, value)) = STATIC_TABLE.get(index - 1) {
+            return Ok((name.to_string(), value.to_string()));
+        }
+
+        self.table
+            .get(index - 1 - STATIC_TABLE.len())
+            .cloned()
+            .with_context(|| format!("HPACK index {index} out of range"))
+    }
+
+    fn decode_literal(
+        &mut self,
+        block: &mut &[u8],
+        prefix_bits: u8,
+    ) -> anyhow::Result<(String, String)> {
+        let name = match decode_int(block, prefix_bits)? {
+            0 => decode_str(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, decode_str(block)?))
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        if size <= self.max_table_size {
+            self.table_size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    /// Makes room for an entry of `incoming` bytes, which may empty the table.
+    fn evict(&mut self, incoming: usize) {
+        while self.table_size + incoming > self.max_table_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.table_size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+fn decode_int(block: &mut &[u8], prefix_bits: u8) -> anyhow::Result<usize> {
+    let (&first, rest) = block.split_first().context("Truncated HPACK integer")?;
+    *block = rest;
+
+    let max_prefix = (1usize << prefix_bits) - 1;
+    let mut value = first as usize & max_prefix;
+    if value < max_prefix {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let (&b, rest) = block.split_first().context("Truncated HPACK integer")?;
+        *block = rest;
+        ensure!(shift <= 28, "HPACK integer too large");
+        value += (b as usize & 0x7F) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_str(block: &mut &[u8]) -> anyhow::Result<String> {
+    let huffman = block.first().is_some_and(|b| b & 0x80 != 0);
+    let len = decode_int(block, 7)?;
+    ensure!(block.len() >= len, "Truncated HPACK string");
+    let (s, rest) = block.split_at(len);
+    *block = rest;
+
+    let s = if huffman {
+        huffman_decode(s)?
+    } else {
+        s.to_vec()
+    };
+
+    String::from_utf8(s).context("Header string is not UTF-8")
+}
+
+// Marks a node of the Huffman tree that is a symbol rather than another node
+const HUFFMAN_LEAF: u16 = 0x8000;
+const HUFFMAN_EOS: u16 = HUFFMAN_LEAF | 256;
+
+/// The codes as a binary tree: the children of each node for a 0 and a 1 bit. The root is
+/// node 0, so 0 never appears as a child.
+static HUFFMAN_TREE: LazyLock<Vec<[u16; 2]>> = LazyLock::new(|| {
+    let mut tree = vec![[0u16; 2]];
+    for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+        let mut node = 0;
+        for shift in (1..len).rev() {
+            let bit = ((code >> shift) & 1) as usize;
+            if tree[node][bit] == 0 {
+                tree.push([0, 0]);
+                tree[node][bit] = (tree.len() - 1) as u16;
+            }
+            node = tree[node][bit] as usize;
+        }
+        tree[node][(code & 1) as usize] = HUFFMAN_LEAF | symbol as u16;
+    }
+    tree
+});
+
+fn huffman_decode(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let tree = &*HUFFMAN_TREE;
+    let mut out = vec![];
+    // Bits since the last symbol, and whether they were all ones
+    let (mut node, mut len, mut all_ones) = (0usize, 0u8, true);
+    for byte in data {
+        for shift in (0..8).rev() {
+            let bit = (byte >> shift) & 1;
+            len += 1;
+            all_ones &= bit == 1;
+
+            match tree[node][bit as usize] {
+                0 => bail!("Invalid Huffman code"),
+                HUFFMAN_EOS => bail!("EOS in Huffman coded string"),
+                next if next & HUFFMAN_LEAF != 0 => {
+                    out.push(next as u8);
+                    (node, len, all_ones) = (0, 0, true);
+                }
+                next => node = next as usize,
+            }
+        }
+    }
+
+    // What's left must be padding: the start of EOS, which is all ones
+    ensure!(len < 8 && all_ones, "Invalid Huffman padding");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hpack_round_trip_works() {
+        let headers: Headers = vec![
+            (":method".to_string(), "POST".to_string()),
+            (":path".to_string(), "/abc".to_string()),
+            ("x-custom".to_string(), "a".repeat(300)),
+        ];
+
+        let encoded = encode(&headers);
+        assert_eq!(encoded[0], 0x83);
+        assert_eq!(Decoder::default().decode(&encoded).unwrap(), headers);
+    }
+
+    #[test]
+    fn hpack_dynamic_table_works() {
+        // RFC 7541 C.3.1 and C.3.2: a request, then one reusing its indexed entry
+        let mut decoder = Decoder::default();
+        let first = b"\x82\x86\x84\x41\x0fwww.example.com";
+        let second = b"\x82\x86\x84\xbe\x58\x08no-cache";
+
+        let headers = decoder.decode(first).unwrap();
+        assert_eq!(headers[3], (":authority".into(), "www.example.com".into()));
+
+        let headers = decoder.decode(second).unwrap();
+        assert_eq!(headers[3], (":authority".into(), "www.example.com".into()));
+        assert_eq!(headers[4], ("cache-control".into(), "no-cache".into()));
+    }
+
+    #[test]
+    fn hpack_limits_header_list_size() {
+        // A single large entry, then the same index over and over
+        let mut block = vec![0x40];
+        encode_str(&mut block, "x-large");
+        encode_str(&mut block, &"a".repeat(4000));
+        block.extend(std::iter::repeat_n(0xbe, 100));
+
+        let err = Decoder::default().decode(&block).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn hpack_huffman_works() {
+        // RFC 7541 C.4.1: the first request, with Huffman coding
+        let block = b"\x82\x86\x84\x41\x8c\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\xff";
+        let headers = Decoder::default().decode(block).unwrap();
+        assert_eq!(headers[3], (":authority".into(), "www.example.com".into()));
+
+        assert!(huffman_decode(b"\xf1\xe3\xc2\xe5\xf2\x3a\x6b\xa0\xab\x90\xf4\x00").is_err());
+        assert_eq!(
+            huffman_decode(b"\x25\xa8\x49\xe9\x5b\xb8\xe8\xb4\xbf").unwrap(),
+            b"custom-value"
+        );
+
+        // Every byte, coded with the table the decoder is built from
+        let (mut coded, mut acc, mut bits) = (vec![], 0u64, 0u32);
+        for &(code, len) in &HUFFMAN_CODES[..256] {
+            acc = (acc << len) | code as u64;
+            bits += len as u32;
+            while bits >= 8 {
+                bits -= 8;
+                coded.push((acc >> bits) as u8);
+            }
+        }
+        if bits > 0 {
+            coded.push(((acc << (8 - bits)) | ((1 << (8 - bits)) - 1)) as u8);
+        }
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(huffman_decode(&coded).unwrap(), all);
+    }
+}
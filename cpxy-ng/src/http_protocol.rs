@@ -1,11 +1,14 @@
+use crate::camouflage::Camouflage;
+use crate::h2::header;
+use crate::hpack::Headers;
 use crate::http_stream::HttpStream;
 use crate::http_util::HttpHeaderExt;
+use crate::key_util::KeyHint;
 use crate::protocol;
 use anyhow::{Context, ensure};
 use base64::Engine;
-use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
 use chacha20poly1305::Key;
-use rand::random_range;
 use sha1::Digest;
 use std::fmt::Debug;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
@@ -17,34 +20,22 @@ pub struct Request {
     pub host: String,
 }
 
-static ALL_METHODS: &[&str] = &["GET", "POST", "PATCH", "PUT"];
-
-static USER_AGENTS: &[&str] = &[
-    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/139.0.0.0 Safari/537.36",
-    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/139.0.0.0 Safari/537.36",
-    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/138.0.0.0 Safari/537.36",
-    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/138.0.0.0 Safari/537.36",
-    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:141.0) Gecko/20100101 Firefox/141.0",
-];
-
-const REQUEST_OVERFLOW_HEADER: &str = "Authorization";
-
 impl Request {
+    /// Parses a request, using `find_key` to look up the key it was encrypted with.
     pub async fn parse<S: AsyncRead + Unpin>(
         stream: S,
-        encrypt_key: &Key,
-    ) -> Result<HttpStream<Request, S>, (anyhow::Error, S)> {
+        camouflage: &Camouflage,
+        mut find_key: impl FnMut(&KeyHint) -> Option<Key>,
+    ) -> Result<HttpStream<Request, S>, (anyhow::Error, HttpStream<(), S>)> {
         HttpStream::parse_request(stream, |http_req| {
-            let serialized = format!(
-                "{}{}",
+            let serialized = camouflage.decode_path(
                 http_req.path.context("Expected a URL path but got none")?,
                 http_req
                     .headers
-                    .get_header_value_str(REQUEST_OVERFLOW_HEADER)
-                    .unwrap_or_default()
-            );
+                    .get_header_value_str(&camouflage.request_header),
+            )?;
 
-            let request = protocol::Request::deserialize(&serialized, encrypt_key)
+            let request = protocol::Request::deserialize(&serialized, &mut find_key)
                 .context("Deserializing request from URL path")?;
 
             ensure!(
@@ -56,7 +47,7 @@ impl Request {
             let websocket_key = http_req
                 .headers
                 .get_header_value("Sec-WebSocket-Key")
-                .and_then(|value| BASE64_URL_SAFE_NO_PAD.decode(value).ok())
+                .and_then(|value| BASE64_STANDARD.decode(value).ok())
                 .context("Expected Sec-WebSocket-Key header for websocket request")?;
 
             let host = http_req
@@ -75,43 +66,101 @@ impl Request {
         .await
     }
 
+    /// Parses a request carried by the headers of an HTTP/2 request.
+    pub fn from_h2_headers(
+        headers: &[(String, String)],
+        camouflage: &Camouflage,
+        mut find_key: impl FnMut(&KeyHint) -> Option<Key>,
+    ) -> anyhow::Result<Request> {
+        let serialized = camouflage.decode_path(
+            header(headers, ":path").context("Expected a :path but got none")?,
+            header(headers, &camouflage.request_header),
+        )?;
+
+        let request = protocol::Request::deserialize(&serialized, &mut find_key)
+            .context("Deserializing request from :path")?;
+
+        Ok(Request {
+            request,
+            websocket_key: vec![],
+            host: header(headers, ":authority")
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+
+    /// The headers of an HTTP/2 request carrying this request, as a POST whose body is the
+    /// tunnel.
+    pub fn to_h2_headers(
+        &self,
+        encrypt_key: &Key,
+        camouflage: &Camouflage,
+    ) -> anyhow::Result<Headers> {
+        let request = self
+            .request
+            .serialize(encrypt_key)
+            .context("Serializing request")?;
+
+        let (path, overflow) = camouflage.encode_path(&request);
+
+        let mut headers: Headers = vec![
+            (":method".into(), "POST".into()),
+            (":scheme".into(), "https".into()),
+            (":authority".into(), self.host.clone()),
+            (":path".into(), path),
+            ("content-type".into(), "application/octet-stream".into()),
+        ];
+        if !overflow.is_empty() {
+            headers.push((camouflage.request_header.to_ascii_lowercase(), overflow));
+        }
+        if let Some(user_agent) = camouflage.random_user_agent() {
+            headers.push(("user-agent".into(), user_agent.into()));
+        }
+        Ok(headers)
+    }
+
     pub async fn send_over_http(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
         encrypt_key: &Key,
+        camouflage: &Camouflage,
     ) -> anyhow::Result<()> {
         let request = self
             .request
             .serialize(encrypt_key)
-            .context("Serializing request as URL path segments")?;
+            .context("Serializing request")?;
 
-        let method = ALL_METHODS[random_range(0..ALL_METHODS.len())];
+        // Proxies only upgrade GET requests to WebSocket, as RFC 6455 requires
+        let method = if self.request.websocket_framing {
+            "GET"
+        } else {
+            camouflage.random_method()
+        };
 
-        let (path, overflow) = request.split_at(request.len().min(25));
+        let (path, overflow) = camouflage.encode_path(&request);
 
         let mut http_request = vec![0u8; 0];
         use std::io::Write;
-        let _ = write!(&mut http_request, "{method} /{path} HTTP/1.1\r\n");
+        let _ = write!(&mut http_request, "{method} {path} HTTP/1.1\r\n");
         let _ = write!(&mut http_request, "Host: {}\r\n", self.host);
         let _ = write!(&mut http_request, "Upgrade: websocket\r\n");
         let _ = write!(&mut http_request, "Connection: Upgrade\r\n");
-        let _ = write!(&mut http_request, "Sec-WebSocket-Version: 14\r\n");
+        let _ = write!(&mut http_request, "Sec-WebSocket-Version: 13\r\n");
         let _ = write!(
             &mut http_request,
             "Sec-WebSocket-Key: {}\r\n",
-            BASE64_URL_SAFE_NO_PAD.encode(&self.websocket_key)
+            BASE64_STANDARD.encode(&self.websocket_key)
         );
-        if overflow.len() > 0 {
+        if !overflow.is_empty() {
             let _ = write!(
                 &mut http_request,
-                "{REQUEST_OVERFLOW_HEADER}: {overflow}\r\n"
+                "{}: {overflow}\r\n",
+                camouflage.request_header
             );
         }
-        let _ = write!(
-            &mut http_request,
-            "User-Agent: {}\r\n",
-            USER_AGENTS[random_range(0..USER_AGENTS.len())]
-        );
+        if let Some(user_agent) = camouflage.random_user_agent() {
+            let _ = write!(&mut http_request, "User-Agent: {user_agent}\r\n");
+        }
         http_request.extend_from_slice(b"\r\n");
 
         stream
@@ -127,46 +176,100 @@ pub struct Response {
     pub websocket_key: Vec<u8>,
 }
 
+/// Computes `Sec-WebSocket-Accept` for the raw bytes of a `Sec-WebSocket-Key`, as per RFC 6455.
+pub fn websocket_accept(websocket_key: &[u8]) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(BASE64_STANDARD.encode(websocket_key));
+    hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    BASE64_STANDARD.encode(hasher.finalize())
+}
+
 impl Response {
+    /// Parses the response to a request that was sent with `websocket_key`.
     pub async fn parse<S: AsyncRead + Unpin>(
         stream: S,
         encrypt_key: &Key,
-    ) -> Result<HttpStream<Response, S>, (anyhow::Error, S)> {
+        camouflage: &Camouflage,
+        websocket_key: &[u8],
+    ) -> Result<HttpStream<Response, S>, (anyhow::Error, HttpStream<(), S>)> {
         HttpStream::parse_response(stream, |http_res| {
             let bytes = http_res
                 .headers
-                .get_header_value(PROTOCOL_RESPONSE_HEADER)
+                .get_header_value(&camouflage.response_header)
                 .context("Unable to find response header")?;
 
             let bytes = BASE64_URL_SAFE_NO_PAD
                 .decode(bytes)
                 .context("Base64 decoding response header failed")?;
 
-            let websocket_key = http_res
+            let accept_key = http_res
                 .headers
                 .get_header_value("Sec-WebSocket-Accept")
-                .context("Missing Sec-WebSocket-Accept header")
-                .and_then(|value| {
-                    BASE64_URL_SAFE_NO_PAD
-                        .decode(value)
-                        .context("Error decode key as b64")
-                })?;
+                .context("Missing Sec-WebSocket-Accept header")?;
+
+            ensure!(
+                accept_key == websocket_accept(websocket_key).as_bytes(),
+                "Sec-WebSocket-Accept doesn't match the key sent"
+            );
 
             let response = protocol::Response::deserialize(&bytes, encrypt_key)
                 .context("Error deserialize response")?;
 
             Ok(Response {
                 response,
-                websocket_key,
+                websocket_key: websocket_key.to_vec(),
             })
         })
         .await
     }
 
+    /// Parses a response carried by the headers of an HTTP/2 response.
+    pub fn from_h2_headers(
+        headers: &[(String, String)],
+        encrypt_key: &Key,
+        camouflage: &Camouflage,
+    ) -> anyhow::Result<Response> {
+        let bytes = header(headers, &camouflage.response_header)
+            .context("Unable to find response header")?;
+
+        let bytes = BASE64_URL_SAFE_NO_PAD
+            .decode(bytes)
+            .context("Base64 decoding response header failed")?;
+
+        let response = protocol::Response::deserialize(&bytes, encrypt_key)
+            .context("Error deserialize response")?;
+
+        Ok(Response {
+            response,
+            websocket_key: vec![],
+        })
+    }
+
+    pub fn to_h2_headers(
+        &self,
+        encrypt_key: &Key,
+        camouflage: &Camouflage,
+    ) -> anyhow::Result<Headers> {
+        let response = self
+            .response
+            .serialize(encrypt_key)
+            .context("Serializing response")?;
+
+        Ok(vec![
+            (":status".into(), "200".into()),
+            ("content-type".into(), "application/octet-stream".into()),
+            (
+                camouflage.response_header.to_ascii_lowercase(),
+                BASE64_URL_SAFE_NO_PAD.encode(&response),
+            ),
+        ])
+    }
+
     pub async fn send_over_http(
         &self,
         stream: &mut (impl AsyncWrite + Unpin),
         encrypt_key: &Key,
+        camouflage: &Camouflage,
     ) -> anyhow::Result<()> {
         let response = self
             .response
@@ -174,14 +277,11 @@ impl Response {
             .context("Serializing response")?;
         let response = BASE64_URL_SAFE_NO_PAD.encode(&response);
 
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&self.websocket_key);
-        hasher.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-        let accept_key = hasher.finalize();
-        let accept_key_b64 = BASE64_URL_SAFE_NO_PAD.encode(&accept_key);
+        let accept_key_b64 = websocket_accept(&self.websocket_key);
 
         let response = format!(
-            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept_key_b64}\r\n{PROTOCOL_RESPONSE_HEADER}: {response}\r\n\r\n",
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept_key_b64}\r\n{}: {response}\r\n\r\n",
+            camouflage.response_header
         );
 
         stream
@@ -192,8 +292,6 @@ impl Response {
     }
 }
 
-const PROTOCOL_RESPONSE_HEADER: &str = "X-Cache-Result";
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -207,55 +305,147 @@ mod tests {
     async fn http_protocol_works() {
         let (mut client, mut server) = tokio::io::duplex(32);
         let encrypt_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let camouflage = Camouflage::from_query_str(
+            "path_prefix=/api&path_encoding=rest&req_header=X-Token&resp_header=X-Trace&methods=PUT",
+        )
+        .unwrap();
 
         let request = Request {
             request: protocol::Request {
+                mode: protocol::TunnelMode::Mux,
                 host: "google.com".to_string(),
                 port: 23,
                 tls: true,
                 client_send_cipher: Configuration::random_partial(NonZeroUsize::new(32).unwrap()),
                 server_send_cipher: Configuration::random_full(),
                 initial_plaintext: vec![1, 2, 3, 4, 5],
+                initial_response_wait_ms: None,
                 timestamp_epoch_seconds: 12345,
+                nonce: [2u8; 16],
+                websocket_framing: true,
+                client_ephemeral_key: Some([4u8; 32]),
+                padding: vec![0u8; 42],
+                max_padding_len: 256,
+                padded_packets: 4,
+                addresses: vec![],
+                trust_addresses: false,
             },
-            websocket_key: vec![0u8; 12],
+            websocket_key: vec![0u8; 16],
             host: "example.com".to_string(),
         };
 
         let do_parse_request = async {
-            Request::parse(&mut server, &encrypt_key)
+            Request::parse(&mut server, &camouflage, |_| Some(encrypt_key))
                 .await
                 .map_err(|e| e.0)
         };
 
         let (_, received_request) = try_join!(
-            request.send_over_http(&mut client, &encrypt_key),
+            request.send_over_http(&mut client, &encrypt_key, &camouflage),
             do_parse_request
         )
         .expect("To send/receive request");
 
         assert_eq!(&request, received_request.head());
 
+        // WebSocket upgrades are always GET, whatever the camouflage says
+        let mut sent = vec![];
+        request
+            .send_over_http(&mut sent, &encrypt_key, &camouflage)
+            .await
+            .unwrap();
+        assert!(sent.starts_with(b"GET "));
+
         let response = Response {
             response: protocol::Response::Success {
                 initial_response: vec![6, 7, 8, 9, 10],
                 timestamp_epoch_seconds: 54321,
+                server_ephemeral_key: None,
+                padding: vec![],
             },
-            websocket_key: request.websocket_key,
+            websocket_key: request.websocket_key.clone(),
         };
 
         let do_parse_response = async {
-            Response::parse(&mut server, &encrypt_key)
-                .await
-                .map_err(|e| e.0)
+            Response::parse(
+                &mut server,
+                &encrypt_key,
+                &camouflage,
+                &request.websocket_key,
+            )
+            .await
+            .map_err(|e| e.0)
         };
 
         let (_, received_response) = try_join!(
-            response.send_over_http(&mut client, &encrypt_key),
+            response.send_over_http(&mut client, &encrypt_key, &camouflage),
             do_parse_response,
         )
         .expect("To send/receive response");
 
         assert_eq!(response.response, received_response.head().response);
     }
+
+    #[test]
+    fn h2_headers_work() {
+        let encrypt_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let camouflage = Camouflage::from_query_str("path_encoding=query").unwrap();
+        let request = Request {
+            request: protocol::Request {
+                mode: protocol::TunnelMode::Stream,
+                host: "google.com".to_string(),
+                port: 443,
+                tls: false,
+                client_send_cipher: Configuration::random_aead(),
+                server_send_cipher: Configuration::random_aead(),
+                initial_plaintext: vec![7u8; 100],
+                initial_response_wait_ms: Some(0),
+                timestamp_epoch_seconds: 12345,
+                nonce: [3u8; 16],
+                websocket_framing: false,
+                client_ephemeral_key: None,
+                padding: vec![],
+                max_padding_len: 0,
+                padded_packets: 0,
+                addresses: vec![],
+                trust_addresses: false,
+            },
+            websocket_key: vec![],
+            host: "example.com".to_string(),
+        };
+
+        let headers = request.to_h2_headers(&encrypt_key, &camouflage).unwrap();
+        assert!(
+            headers
+                .iter()
+                .all(|(name, _)| name.to_ascii_lowercase() == *name)
+        );
+        assert_eq!(
+            Request::from_h2_headers(&headers, &camouflage, |_| Some(encrypt_key)).unwrap(),
+            request
+        );
+
+        let response = Response {
+            response: protocol::Response::Error {
+                code: protocol::ErrorCode::TimedOut,
+                timestamp_epoch_seconds: 54321,
+                padding: vec![],
+            },
+            websocket_key: vec![],
+        };
+        let headers = response.to_h2_headers(&encrypt_key, &camouflage).unwrap();
+        assert_eq!(
+            Response::from_h2_headers(&headers, &encrypt_key, &camouflage)
+                .unwrap()
+                .response,
+            response.response
+        );
+    }
+
+    #[test]
+    fn websocket_accept_matches_rfc() {
+        // The sample handshake from RFC 6455 section 1.3
+        let key = BASE64_STANDARD.decode("dGhlIHNhbXBsZSBub25jZQ==").unwrap();
+        assert_eq!(websocket_accept(&key), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
 }
@@ -3,7 +3,9 @@ use crate::encrypt_stream::Configuration;
 use crate::http_stream::HttpStream;
 use crate::outbound::{OutboundHost, OutboundRequest};
 use crate::protocol;
+use crate::time_util::now_epoch_seconds;
 use anyhow::{Context, ensure};
+use rand::random;
 use tokio::io::AsyncRead;
 use url::Url;
 
@@ -31,26 +33,46 @@ impl From<ProxyRequest> for protocol::Request {
     fn from(value: ProxyRequest) -> Self {
         match value {
             ProxyRequest::Http(req) => Self {
+                mode: protocol::TunnelMode::Stream,
                 host: req.host,
                 port: req.port,
                 tls: req.tls,
-                server_send_cipher: Configuration::random_full(),
+                server_send_cipher: Configuration::random_aead(),
                 initial_plaintext: req.payload,
-                client_send_cipher: Configuration::random_full(),
-                timestamp_epoch_seconds: 0,
+                initial_response_wait_ms: None,
+                client_send_cipher: Configuration::random_aead(),
+                timestamp_epoch_seconds: now_epoch_seconds(),
+                nonce: random(),
+                websocket_framing: false,
+                client_ephemeral_key: None,
+                padding: vec![],
+                max_padding_len: 0,
+                padded_packets: 0,
+                addresses: vec![],
+                trust_addresses: false,
             },
 
             ProxyRequest::Socket(req) => {
                 let (client_send_cipher, server_send_cipher) =
                     select_cipher_based_on_port(req.port);
                 Self {
+                    mode: protocol::TunnelMode::Stream,
                     host: req.host,
                     port: req.port,
                     tls: true,
                     server_send_cipher,
                     initial_plaintext: vec![],
+                    initial_response_wait_ms: None,
                     client_send_cipher,
-                    timestamp_epoch_seconds: 0,
+                    timestamp_epoch_seconds: now_epoch_seconds(),
+                    nonce: random(),
+                    websocket_framing: false,
+                    client_ephemeral_key: None,
+                    padding: vec![],
+                    max_padding_len: 0,
+                    padded_packets: 0,
+                    addresses: vec![],
+                    trust_addresses: false,
                 }
             }
         }
@@ -79,7 +101,7 @@ impl From<ProxyRequest> for OutboundRequest {
 
 pub async fn parse_http_proxy_stream<S: AsyncRead + Unpin>(
     stream: S,
-) -> Result<HttpStream<ProxyRequest, S>, (anyhow::Error, S)> {
+) -> Result<HttpStream<ProxyRequest, S>, (anyhow::Error, HttpStream<(), S>)> {
     HttpStream::parse_request(stream, |req| {
         let method = req.method.context("Expecting http method")?;
 
@@ -87,8 +109,10 @@ pub async fn parse_http_proxy_stream<S: AsyncRead + Unpin>(
             let (host, port_str) = req
                 .path
                 .context("Expecting CONNECT path")?
-                .split_once(':')
+                .rsplit_once(':')
                 .context("Expecting host:port in CONNECT path")?;
+            // IPv6 addresses come in brackets
+            let host = host.trim_start_matches('[').trim_end_matches(']');
 
             let port: u16 = port_str.parse().context("Expecting port")?;
 
@@ -106,6 +130,7 @@ pub async fn parse_http_proxy_stream<S: AsyncRead + Unpin>(
             let scheme = url.scheme();
 
             let host = url.host_str().context("Expecting host in HTTP request")?;
+            let host = host.trim_start_matches('[').trim_end_matches(']');
             let port = url
                 .port_or_known_default()
                 .context("Port is not specified or unknown")?;
@@ -209,4 +234,19 @@ mod tests {
             })
         );
     }
+
+    #[tokio::test]
+    async fn proxy_request_parsing_works_ipv6() {
+        let mut req =
+            b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: [2001:db8::1]:443\r\n\r\n".as_slice();
+        let req = parse_http_proxy_stream(&mut req).await.expect("To parse");
+
+        assert_eq!(
+            req.head(),
+            &ProxyRequest::Socket(ProxyRequestSocket {
+                host: "2001:db8::1".to_string(),
+                port: 443,
+            })
+        );
+    }
 }
@@ -1,5 +1,7 @@
+use crate::http_util::{ParseError, ParsedHead};
 use bytes::{Buf, Bytes};
 use pin_project_lite::pin_project;
+use std::fmt::{Debug, Formatter};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
@@ -7,6 +9,8 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 pin_project! {
     pub struct HttpStream<H, S> {
         head: H,
+        // Everything read while parsing the head, and what's left of it to hand out
+        raw: Bytes,
         parse_remnant: Bytes,
         #[pin]
         inner: S,
@@ -14,44 +18,65 @@ pin_project! {
 }
 
 impl<H, S: AsyncRead + Unpin> HttpStream<H, S> {
+    /// Parses a response head. On failure the stream comes back with everything read so far
+    /// still unread.
     pub async fn parse_response(
         mut inner: S,
         parser: impl FnMut(&httparse::Response<'_, '_>) -> anyhow::Result<H>,
-    ) -> Result<Self, (anyhow::Error, S)>
+    ) -> Result<Self, (anyhow::Error, HttpStream<(), S>)>
     where
         S: AsyncRead + Unpin,
     {
-        let (head, parse_remnant) =
-            match super::http_util::parse_http_response(&mut inner, parser).await {
-                Ok(v) => v,
-                Err(e) => return Err((e, inner)),
-            };
-
-        Ok(Self {
-            head,
-            parse_remnant,
-            inner,
-        })
+        let result = super::http_util::parse_http_response(&mut inner, parser).await;
+        Self::from_parse_result(result, inner)
     }
 
+    /// Parses a request head. On failure the stream comes back with everything read so far
+    /// still unread.
     pub async fn parse_request(
         mut inner: S,
         parser: impl FnMut(&httparse::Request<'_, '_>) -> anyhow::Result<H>,
-    ) -> Result<Self, (anyhow::Error, S)>
+    ) -> Result<Self, (anyhow::Error, HttpStream<(), S>)>
     where
         S: AsyncRead + Unpin,
     {
-        let (head, parse_remnant) =
-            match super::http_util::parse_http_request(&mut inner, parser).await {
-                Ok(v) => v,
-                Err(e) => return Err((e, inner)),
-            };
+        let result = super::http_util::parse_http_request(&mut inner, parser).await;
+        Self::from_parse_result(result, inner)
+    }
 
-        Ok(Self {
-            head,
-            parse_remnant,
-            inner,
-        })
+    fn from_parse_result(
+        result: Result<ParsedHead<H>, ParseError>,
+        inner: S,
+    ) -> Result<Self, (anyhow::Error, HttpStream<(), S>)> {
+        match result {
+            Ok((head, raw, head_len)) => Ok(Self {
+                head,
+                parse_remnant: raw.slice(head_len..),
+                raw,
+                inner,
+            }),
+            Err((e, raw)) => Err((
+                e,
+                HttpStream {
+                    head: (),
+                    parse_remnant: raw.clone(),
+                    raw,
+                    inner,
+                },
+            )),
+        }
+    }
+}
+
+impl<H: Debug, S> Debug for HttpStream<H, S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpStream")
+            .field("head", &self.head)
+            .field(
+                "parse_remnant",
+                &format!("<{} bytes>", self.parse_remnant.len()),
+            )
+            .finish_non_exhaustive()
     }
 }
 
@@ -67,6 +92,7 @@ impl<H, S> HttpStream<H, S> {
     pub fn take_head(self) -> (H, HttpStream<(), S>) {
         let Self {
             head,
+            raw,
             parse_remnant,
             inner,
         } = self;
@@ -75,12 +101,24 @@ impl<H, S> HttpStream<H, S> {
             head,
             HttpStream {
                 head: (),
+                raw,
                 parse_remnant,
                 inner,
             },
         )
     }
 
+    /// Gives back the stream as it was before parsing, so the head can be read again.
+    /// Only meaningful before anything past the head has been read.
+    pub fn rewind(self) -> HttpStream<(), S> {
+        HttpStream {
+            head: (),
+            parse_remnant: self.raw.clone(),
+            raw: self.raw,
+            inner: self.inner,
+        }
+    }
+
     pub fn into_inner(self) -> S {
         self.inner
     }
@@ -184,4 +222,20 @@ mod tests {
             .expect("To read until the end");
         assert_eq!(actual_read, b"Hello, world");
     }
+
+    #[tokio::test]
+    async fn failed_parse_keeps_the_bytes_read() {
+        let request_text = b"NOT HTTP AT ALL\r\n\r\nmore";
+
+        let (_, stream) = HttpStream::parse_request(Cursor::new(request_text), |_| Ok(()))
+            .await
+            .expect_err("Parsing garbage succeeds");
+
+        let mut actual_read = vec![];
+        BufReader::new(stream)
+            .read_to_end(&mut actual_read)
+            .await
+            .expect("To read until the end");
+        assert_eq!(actual_read, request_text);
+    }
 }
@@ -1,12 +1,19 @@
-use anyhow::{Context, bail, ensure};
+use anyhow::{Context, format_err};
 use bytes::{Buf, Bytes};
 use std::io::Cursor;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// The result of parsing an HTTP head: the parsed value, everything read from the stream and
+/// how much of that was the head.
+pub type ParsedHead<T> = (T, Bytes, usize);
+
+/// A parse error, with everything read from the stream before it happened.
+pub type ParseError = (anyhow::Error, Bytes);
+
 pub async fn parse_http_request<T>(
     stream: &mut (impl AsyncRead + Unpin),
     mut parser: impl FnMut(&httparse::Request<'_, '_>) -> anyhow::Result<T>,
-) -> anyhow::Result<(T, Bytes)> {
+) -> Result<ParsedHead<T>, ParseError> {
     parse_http(stream, |data| {
         let mut headers = [httparse::EMPTY_HEADER; 32];
         let mut http_req = httparse::Request::new(&mut headers);
@@ -24,7 +31,7 @@ pub async fn parse_http_request<T>(
 pub async fn parse_http_response<T>(
     stream: &mut (impl AsyncRead + Unpin),
     mut parser: impl FnMut(&httparse::Response<'_, '_>) -> anyhow::Result<T>,
-) -> anyhow::Result<(T, Bytes)> {
+) -> Result<ParsedHead<T>, ParseError> {
     parse_http(stream, |data| {
         let mut headers = [httparse::EMPTY_HEADER; 32];
         let mut http_res = httparse::Response::new(&mut headers);
@@ -42,43 +49,44 @@ pub async fn parse_http_response<T>(
 async fn parse_http<T>(
     stream: &mut (impl AsyncRead + Unpin),
     mut parser: impl FnMut(&[u8]) -> anyhow::Result<Option<(T, usize)>>,
-) -> anyhow::Result<(T, Bytes)> {
+) -> Result<ParsedHead<T>, ParseError> {
     let mut buf = Cursor::new(vec![0u8; 256]);
+    let read_so_far = |buf: Cursor<Vec<u8>>| {
+        let read_position = buf.position() as usize;
+        Bytes::from(buf.into_inner()).slice(..read_position)
+    };
 
     while buf.has_remaining() {
-        let byte_read = stream
+        let byte_read = match stream
             .read(buf.remaining_buf())
             .await
-            .context("Reading http request")?;
-        ensure!(
-            byte_read != 0,
-            "Connection closed before end of HTTP request"
-        );
+            .context("Reading http request")
+        {
+            Ok(0) => {
+                return Err((
+                    format_err!("Connection closed before end of HTTP request"),
+                    read_so_far(buf),
+                ));
+            }
+            Ok(n) => n,
+            Err(e) => return Err((e, read_so_far(buf))),
+        };
         buf.advance(byte_read);
 
-        match parser(buf.filled_buf())? {
-            Some((result, len)) => {
-                let read_position = buf.position() as usize;
-                let extra_data = if read_position > len {
-                    // We have extra data after the HTTP request, which could be the body, return
-                    // this data
-                    Bytes::from(buf.into_inner()).slice(len..read_position)
-                } else {
-                    Bytes::default()
-                };
-
-                return Ok((result, extra_data));
-            }
-            None => {
+        match parser(buf.filled_buf()) {
+            // Anything read after the head, which could be the body, stays in the buffer
+            Ok(Some((result, len))) => return Ok((result, read_so_far(buf), len)),
+            Ok(None) => {
                 if buf.position() == buf.get_ref().len() as u64 {
                     let new_size = (buf.get_ref().len() * 2).max(65536);
                     buf.get_mut().resize(new_size, 0);
                 }
             }
+            Err(e) => return Err((e, read_so_far(buf))),
         }
     }
 
-    bail!("HTTP head too large")
+    Err((format_err!("HTTP head too large"), read_so_far(buf)))
 }
 
 pub trait HttpHeaderExt {
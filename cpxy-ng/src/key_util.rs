@@ -1,12 +1,133 @@
+use anyhow::{Context, bail, ensure, format_err};
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use chacha20poly1305::Key;
+use hmac::{Hmac, Mac};
 use rand::RngCore;
-use sha2::Digest;
+use sha2::{Digest, Sha256};
 
 pub fn derive_password(password: &str) -> [u8; 32] {
     sha2::Sha256::digest(password.as_bytes()).into()
 }
 
+/// How the configured secret turns into a key.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kdf {
+    /// A single SHA-256 over the password, kept for existing deployments
+    Sha256,
+    /// Argon2id with a salt shared by the deployment
+    Argon2id { salt: String },
+    /// The secret is the key itself, as 32 bytes in URL-safe base64
+    Raw,
+}
+
+impl Kdf {
+    pub fn new(name: &str, salt: Option<&str>) -> anyhow::Result<Self> {
+        match name {
+            "sha256" => Ok(Self::Sha256),
+            "argon2id" => {
+                let salt = salt.context("Argon2id requires a salt")?;
+                ensure!(salt.len() >= 8, "Argon2id salt must be at least 8 bytes");
+                Ok(Self::Argon2id {
+                    salt: salt.to_string(),
+                })
+            }
+            "raw" => Ok(Self::Raw),
+            name => bail!("Unknown KDF: {name}, expected one of sha256, argon2id or raw"),
+        }
+    }
+
+    pub fn derive(&self, secret: &str) -> anyhow::Result<[u8; 32]> {
+        match self {
+            Self::Sha256 => Ok(derive_password(secret)),
+            Self::Argon2id { salt } => {
+                let mut key = [0u8; 32];
+                argon2::Argon2::default()
+                    .hash_password_into(secret.as_bytes(), salt.as_bytes(), &mut key)
+                    .map_err(|e| format_err!("Error deriving key with Argon2id: {e}"))?;
+                Ok(key)
+            }
+            Self::Raw => BASE64_URL_SAFE_NO_PAD
+                .decode(secret)
+                .context("Error base64 decoding raw key")?
+                .try_into()
+                .map_err(|_| format_err!("Raw key must be 32 bytes")),
+        }
+    }
+}
+
+/// Identifies a key to the server without revealing anything about the key itself: a random
+/// salt, then a truncated HMAC of the salt under the key. With a fresh salt every time, hints
+/// don't link the requests of a user either.
+pub type KeyHint = [u8; 16];
+
+const KEY_HINT_SALT_LEN: usize = 8;
+
+fn key_hint_mac(key: &Key, salt: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(b"cpxy-ng key hint");
+    mac.update(salt);
+    mac
+}
+
+pub fn key_hint(key: &Key) -> KeyHint {
+    let mut hint = KeyHint::default();
+    let (salt, tag) = hint.split_at_mut(KEY_HINT_SALT_LEN);
+    rand::rng().fill_bytes(salt);
+    let mac = key_hint_mac(key, salt).finalize().into_bytes();
+    tag.copy_from_slice(&mac[..tag.len()]);
+    hint
+}
+
+/// Whether `hint` was made with `key`, in constant time.
+pub fn key_hint_matches(key: &Key, hint: &KeyHint) -> bool {
+    let (salt, tag) = hint.split_at(KEY_HINT_SALT_LEN);
+    key_hint_mac(key, salt).verify_truncated_left(tag).is_ok()
+}
+
 pub fn random_vec(len: usize) -> Vec<u8> {
     let mut buf = vec![0u8; len];
     rand::rng().fill_bytes(&mut buf);
     buf
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kdf_works() {
+        assert_eq!(
+            Kdf::new("sha256", None)
+                .unwrap()
+                .derive("password")
+                .unwrap(),
+            derive_password("password")
+        );
+
+        let argon2 = Kdf::new("argon2id", Some("deployment salt")).unwrap();
+        let key = argon2.derive("password").unwrap();
+        assert_eq!(key, argon2.derive("password").unwrap());
+        assert_ne!(key, derive_password("password"));
+        assert!(Kdf::new("argon2id", None).is_err());
+        assert!(Kdf::new("argon2id", Some("short")).is_err());
+
+        let raw = Kdf::new("raw", None).unwrap();
+        assert_eq!(
+            raw.derive(&BASE64_URL_SAFE_NO_PAD.encode(key)).unwrap(),
+            key
+        );
+        assert!(raw.derive("too short").is_err());
+
+        assert!(Kdf::new("md5", None).is_err());
+    }
+
+    #[test]
+    fn key_hint_works() {
+        let key: Key = derive_password("password").into();
+        let hint = key_hint(&key);
+        assert_ne!(hint, key_hint(&key));
+        assert!(key_hint_matches(&key, &hint));
+        assert!(!key_hint_matches(&derive_password("other").into(), &hint));
+    }
+}
@@ -1,15 +1,24 @@
+pub mod camouflage;
 pub mod cipher_select;
 pub mod either_stream;
 pub mod encrypt_stream;
 pub mod geoip;
+pub mod h2;
+pub mod handshake;
+pub mod hpack;
 pub mod http_protocol;
 pub mod http_proxy;
 pub mod http_stream;
 pub mod http_util;
 pub mod key_util;
+pub mod mux;
 pub mod outbound;
+pub mod padding_stream;
 pub mod protocol;
+pub mod relay;
 pub mod time_util;
 pub mod tls_stream;
+pub mod udp;
+pub mod websocket_stream;
 
 pub use chacha20poly1305::Key;
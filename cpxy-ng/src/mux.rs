@@ -0,0 +1,803 @@
+use crate::protocol;
+use crate::time_util::now_epoch_seconds;
+use anyhow::{Context, bail, ensure, format_err};
+use bytes::Bytes;
+use rkyv::rancor::Error as RkyvError;
+use rkyv::{Archive, Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{Semaphore, mpsc, oneshot};
+
+const FRAME_OPEN: u8 = 0;
+const FRAME_OPEN_RESULT: u8 = 1;
+const FRAME_DATA: u8 = 2;
+const FRAME_WINDOW_UPDATE: u8 = 3;
+const FRAME_CLOSE: u8 = 4;
+const FRAME_RESET: u8 = 5;
+
+// Frame type (1 byte), stream id (4 bytes), payload length (4 bytes)
+const HEADER_LEN: usize = 9;
+const MAX_PAYLOAD_LEN: usize = 1024 * 1024;
+const MAX_DATA_FRAME_LEN: usize = 16 * 1024;
+const INITIAL_WINDOW: usize = 256 * 1024;
+// How many data frames of a stream can be waiting for the reader. Each window update
+// acknowledges exactly one frame, so the sender can count them too.
+const MAX_QUEUED_FRAMES: usize = 256;
+
+/// What the client asks the server to connect to for each logical stream.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct OpenRequest {
+    pub host: String,
+    pub port: u16,
+    /// See the fields of the same name in [`protocol::Request`]
+    pub addresses: Vec<IpAddr>,
+    pub trust_addresses: bool,
+    pub tls: bool,
+    pub initial_plaintext: Vec<u8>,
+    pub initial_response_wait_ms: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Frame {
+    Open {
+        id: u32,
+        request: Vec<u8>,
+    },
+    OpenResult {
+        id: u32,
+        response: Vec<u8>,
+    },
+    Data {
+        id: u32,
+        data: Bytes,
+    },
+    WindowUpdate {
+        id: u32,
+        increment: u32,
+    },
+    /// The sender won't write to this stream anymore
+    Close {
+        id: u32,
+    },
+    /// The sender has abandoned the stream in both directions
+    Reset {
+        id: u32,
+    },
+}
+
+impl Frame {
+    fn encode(self) -> Vec<u8> {
+        let (frame_type, id, payload) = match self {
+            Frame::Open { id, request } => (FRAME_OPEN, id, Bytes::from(request)),
+            Frame::OpenResult { id, response } => (FRAME_OPEN_RESULT, id, Bytes::from(response)),
+            Frame::Data { id, data } => (FRAME_DATA, id, data),
+            Frame::WindowUpdate { id, increment } => (
+                FRAME_WINDOW_UPDATE,
+                id,
+                Bytes::copy_from_slice(&increment.to_be_bytes()),
+            ),
+            Frame::Close { id } => (FRAME_CLOSE, id, Bytes::new()),
+            Frame::Reset { id } => (FRAME_RESET, id, Bytes::new()),
+        };
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.push(frame_type);
+        buf.extend_from_slice(&id.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    async fn read(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Self>> {
+        let mut header = [0u8; HEADER_LEN];
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Error reading mux frame header"),
+        }
+
+        let frame_type = header[0];
+        let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+        ensure!(len <= MAX_PAYLOAD_LEN, "Mux frame too large: {len} bytes");
+
+        let mut payload = vec![0u8; len];
+        stream
+            .read_exact(&mut payload)
+            .await
+            .context("Error reading mux frame payload")?;
+
+        Ok(Some(match frame_type {
+            FRAME_OPEN => Frame::Open {
+                id,
+                request: payload,
+            },
+            FRAME_OPEN_RESULT => Frame::OpenResult {
+                id,
+                response: payload,
+            },
+            FRAME_DATA => Frame::Data {
+                id,
+                data: payload.into(),
+            },
+            FRAME_WINDOW_UPDATE => Frame::WindowUpdate {
+                id,
+                increment: u32::from_be_bytes(
+                    payload
+                        .try_into()
+                        .map_err(|_| format_err!("Invalid window update payload"))?,
+                ),
+            },
+            FRAME_CLOSE => Frame::Close { id },
+            FRAME_RESET => Frame::Reset { id },
+            _ => bail!("Unknown mux frame type: {frame_type}"),
+        }))
+    }
+}
+
+struct StreamEntry {
+    // None once the peer has closed its sending side
+    data_tx: Option<mpsc::Sender<Bytes>>,
+    // What we may send, in bytes and in frames
+    credit: Arc<Semaphore>,
+    frame_credit: Arc<Semaphore>,
+    // What the peer may still send before we've handed its data on
+    recv_window: usize,
+    local_closed: bool,
+}
+
+impl StreamEntry {
+    fn close(&self) {
+        self.credit.close();
+        self.frame_credit.close();
+    }
+}
+
+#[derive(Default)]
+struct State {
+    closed: bool,
+    streams: HashMap<u32, StreamEntry>,
+    pending_opens: HashMap<u32, oneshot::Sender<Vec<u8>>>,
+    /// Streams the peer has asked to open that haven't been answered yet
+    incoming: HashSet<u32>,
+}
+
+struct Shared {
+    frames_tx: mpsc::Sender<Frame>,
+    state: Mutex<State>,
+    next_id: AtomicU32,
+}
+
+impl Shared {
+    fn attach(
+        self: &Arc<Self>,
+        id: u32,
+        open_result_tx: Option<oneshot::Sender<Vec<u8>>>,
+    ) -> anyhow::Result<DuplexStream> {
+        let (app, pump) = tokio::io::duplex(MAX_DATA_FRAME_LEN * 4);
+        let (pump_r, pump_w) = tokio::io::split(pump);
+        let (data_tx, data_rx) = mpsc::channel(MAX_QUEUED_FRAMES);
+        let credit = Arc::new(Semaphore::new(INITIAL_WINDOW));
+        let frame_credit = Arc::new(Semaphore::new(MAX_QUEUED_FRAMES));
+
+        {
+            let mut state = self.state.lock().unwrap();
+            ensure!(!state.closed, "Mux session closed");
+
+            state.streams.insert(
+                id,
+                StreamEntry {
+                    data_tx: Some(data_tx),
+                    credit: credit.clone(),
+                    frame_credit: frame_credit.clone(),
+                    recv_window: INITIAL_WINDOW,
+                    local_closed: false,
+                },
+            );
+
+            if let Some(tx) = open_result_tx {
+                state.pending_opens.insert(id, tx);
+            }
+        }
+
+        tokio::spawn(pump_outbound(
+            id,
+            pump_r,
+            credit,
+            frame_credit,
+            self.clone(),
+        ));
+        tokio::spawn(pump_inbound(id, pump_w, data_rx, self.clone()));
+        Ok(app)
+    }
+
+    fn remove_stream(&self, id: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.pending_opens.remove(&id);
+        state.incoming.remove(&id);
+        if let Some(entry) = state.streams.remove(&id) {
+            entry.close();
+        }
+    }
+
+    /// Abandons a stream on both sides.
+    async fn reset_stream(&self, id: u32) {
+        self.remove_stream(id);
+        let _ = self.frames_tx.send(Frame::Reset { id }).await;
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.pending_opens.clear();
+        state.incoming.clear();
+        for (_, entry) in state.streams.drain() {
+            entry.close();
+        }
+    }
+}
+
+async fn pump_outbound(
+    id: u32,
+    mut stream: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    frame_credit: Arc<Semaphore>,
+    shared: Arc<Shared>,
+) {
+    let mut buf = vec![0u8; MAX_DATA_FRAME_LEN];
+    loop {
+        let n = match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+
+        // The peer has reset the stream or the session is gone
+        let Ok(frame_permit) = frame_credit.acquire().await else {
+            return;
+        };
+        let Ok(permit) = credit.acquire_many(n as u32).await else {
+            return;
+        };
+        frame_permit.forget();
+        permit.forget();
+
+        let data = Bytes::copy_from_slice(&buf[..n]);
+        if shared
+            .frames_tx
+            .send(Frame::Data { id, data })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    let _ = shared.frames_tx.send(Frame::Close { id }).await;
+
+    let mut state = shared.state.lock().unwrap();
+    if let Some(entry) = state.streams.get_mut(&id) {
+        entry.local_closed = true;
+        if entry.data_tx.is_none() {
+            state.streams.remove(&id);
+        }
+    }
+}
+
+async fn pump_inbound(
+    id: u32,
+    mut stream: WriteHalf<DuplexStream>,
+    mut data_rx: mpsc::Receiver<Bytes>,
+    shared: Arc<Shared>,
+) {
+    while let Some(data) = data_rx.recv().await {
+        if stream.write_all(&data).await.is_err() {
+            // Nobody is reading this stream anymore, tell the peer to stop sending
+            shared.reset_stream(id).await;
+            return;
+        }
+
+        if let Some(entry) = shared.state.lock().unwrap().streams.get_mut(&id) {
+            entry.recv_window += data.len();
+        }
+
+        let increment = data.len() as u32;
+        if shared
+            .frames_tx
+            .send(Frame::WindowUpdate { id, increment })
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+async fn read_loop(
+    mut stream: impl AsyncRead + Unpin,
+    shared: &Arc<Shared>,
+    incoming_tx: Option<mpsc::Sender<IncomingStream>>,
+) -> anyhow::Result<()> {
+    while let Some(frame) = Frame::read(&mut stream).await? {
+        match frame {
+            Frame::Open { id, request } => {
+                let incoming_tx = incoming_tx
+                    .as_ref()
+                    .context("Unexpected open frame on a client session")?;
+
+                let request = rkyv::from_bytes::<OpenRequest, RkyvError>(&request)
+                    .context("Error deserializing mux open request")?;
+
+                let duplicate = {
+                    let mut state = shared.state.lock().unwrap();
+                    state.streams.contains_key(&id) || !state.incoming.insert(id)
+                };
+                if duplicate {
+                    shared.reset_stream(id).await;
+                    continue;
+                }
+
+                let incoming = IncomingStream {
+                    request,
+                    id,
+                    shared: shared.clone(),
+                };
+
+                if incoming_tx.send(incoming).await.is_err() {
+                    break;
+                }
+            }
+
+            Frame::OpenResult { id, response } => {
+                let tx = shared.state.lock().unwrap().pending_opens.remove(&id);
+                if let Some(tx) = tx {
+                    let _ = tx.send(response);
+                }
+            }
+
+            Frame::Data { id, data } => {
+                let overflowed = match shared.state.lock().unwrap().streams.get_mut(&id) {
+                    Some(StreamEntry {
+                        data_tx: Some(tx),
+                        recv_window,
+                        ..
+                    }) if data.len() <= *recv_window => {
+                        *recv_window -= data.len();
+                        matches!(tx.try_send(data), Err(TrySendError::Full(_)))
+                    }
+                    Some(StreamEntry {
+                        data_tx: Some(_), ..
+                    }) => true,
+                    _ => false,
+                };
+
+                // The peer sent more than it was granted
+                if overflowed {
+                    shared.reset_stream(id).await;
+                }
+            }
+
+            Frame::WindowUpdate { id, increment } => {
+                if let Some(entry) = shared.state.lock().unwrap().streams.get(&id) {
+                    entry.credit.add_permits(increment as usize);
+                    entry.frame_credit.add_permits(1);
+                }
+            }
+
+            Frame::Close { id } => {
+                let mut state = shared.state.lock().unwrap();
+                if let Some(entry) = state.streams.get_mut(&id) {
+                    entry.data_tx = None;
+                    if entry.local_closed {
+                        state.streams.remove(&id);
+                    }
+                }
+            }
+
+            Frame::Reset { id } => shared.remove_stream(id),
+        }
+    }
+
+    Ok(())
+}
+
+async fn write_loop(
+    mut stream: impl AsyncWrite + Unpin,
+    mut frames_rx: mpsc::Receiver<Frame>,
+) -> anyhow::Result<()> {
+    while let Some(frame) = frames_rx.recv().await {
+        stream
+            .write_all(&frame.encode())
+            .await
+            .context("Error writing mux frame")?;
+
+        if frames_rx.is_empty() {
+            stream.flush().await.context("Error flushing mux frames")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Carries many logical streams over a single underlying connection. Each stream has its
+/// own flow control window so a slow reader doesn't hold up the rest of the session.
+#[derive(Clone)]
+pub struct MuxSession {
+    shared: Arc<Shared>,
+}
+
+impl MuxSession {
+    fn start<S>(stream: S, incoming_tx: Option<mpsc::Sender<IncomingStream>>) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (frames_tx, frames_rx) = mpsc::channel(64);
+        let shared = Arc::new(Shared {
+            frames_tx,
+            state: Default::default(),
+            next_id: AtomicU32::new(1),
+        });
+
+        let driver_shared = shared.clone();
+        let incoming_closed = incoming_tx.clone();
+        tokio::spawn(async move {
+            let (r, w) = tokio::io::split(stream);
+            let _ = tokio::select! {
+                r = read_loop(r, &driver_shared, incoming_tx) => r,
+                r = write_loop(w, frames_rx) => r,
+                _ = async {
+                    match &incoming_closed {
+                        Some(tx) => tx.closed().await,
+                        None => std::future::pending().await,
+                    }
+                } => Ok(()),
+            };
+            driver_shared.close();
+        });
+
+        Self { shared }
+    }
+
+    /// Starts the client side of a session over an established tunnel.
+    pub fn client<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::start(stream, None)
+    }
+
+    /// Starts the server side of a session, yielding every stream the client opens. Dropping
+    /// the receiver closes the session, streams and all.
+    pub fn server<S>(stream: S) -> mpsc::Receiver<IncomingStream>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        Self::start(stream, Some(incoming_tx));
+        incoming_rx
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    pub async fn open(
+        &self,
+        request: &OpenRequest,
+    ) -> anyhow::Result<(protocol::Response, DuplexStream)> {
+        let payload = rkyv::to_bytes::<RkyvError>(request)
+            .context("Error serializing mux open request")?
+            .to_vec();
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let (result_tx, result_rx) = oneshot::channel();
+        let stream = self.shared.attach(id, Some(result_tx))?;
+
+        self.shared
+            .frames_tx
+            .send(Frame::Open {
+                id,
+                request: payload,
+            })
+            .await
+            .map_err(|_| format_err!("Mux session closed"))?;
+
+        let response = result_rx
+            .await
+            .map_err(|_| format_err!("Mux stream was reset before it was opened"))?;
+
+        let response = rkyv::from_bytes::<protocol::Response, RkyvError>(&response)
+            .context("Error deserializing mux open result")?;
+
+        if matches!(response, protocol::Response::Error { .. }) {
+            self.shared.remove_stream(id);
+        }
+
+        Ok((response, stream))
+    }
+}
+
+/// A stream the client has asked to open, waiting for the server to accept or reject it.
+/// Dropping it unanswered resets the stream.
+pub struct IncomingStream {
+    pub request: OpenRequest,
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl IncomingStream {
+    /// Takes the stream out of those waiting for an answer, failing if the peer has reset
+    /// it in the meantime.
+    fn answer(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.shared.state.lock().unwrap().incoming.remove(&self.id),
+            "Mux stream was reset"
+        );
+        Ok(())
+    }
+
+    async fn send_result(&self, response: &protocol::Response) -> anyhow::Result<()> {
+        let response = rkyv::to_bytes::<RkyvError>(response)
+            .context("Error serializing mux open result")?
+            .to_vec();
+
+        self.shared
+            .frames_tx
+            .send(Frame::OpenResult {
+                id: self.id,
+                response,
+            })
+            .await
+            .map_err(|_| format_err!("Mux session closed"))
+    }
+
+    pub async fn accept(self, initial_response: Vec<u8>) -> anyhow::Result<DuplexStream> {
+        self.answer()?;
+        let stream = self.shared.attach(self.id, None)?;
+        self.send_result(&protocol::Response::Success {
+            initial_response,
+            timestamp_epoch_seconds: now_epoch_seconds(),
+            // The session itself has already been keyed
+            server_ephemeral_key: None,
+            padding: vec![],
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub async fn reject(self, code: protocol::ErrorCode) -> anyhow::Result<()> {
+        self.answer()?;
+        self.send_result(&protocol::Response::Error {
+            code,
+            timestamp_epoch_seconds: now_epoch_seconds(),
+            padding: vec![],
+        })
+        .await
+    }
+}
+
+impl Drop for IncomingStream {
+    fn drop(&mut self) {
+        if self.shared.state.lock().unwrap().incoming.remove(&self.id) {
+            let frames_tx = self.shared.frames_tx.clone();
+            let id = self.id;
+            tokio::spawn(async move {
+                let _ = frames_tx.send(Frame::Reset { id }).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frame_encoding_works() {
+        let frames = vec![
+            Frame::Open {
+                id: 1,
+                request: vec![1, 2, 3],
+            },
+            Frame::Data {
+                id: 2,
+                data: Bytes::from_static(b"hello"),
+            },
+            Frame::WindowUpdate {
+                id: 3,
+                increment: 1024,
+            },
+            Frame::Close { id: 4 },
+            Frame::Reset { id: 5 },
+        ];
+
+        let mut encoded = vec![];
+        for frame in &frames {
+            encoded.extend_from_slice(&frame.clone().encode());
+        }
+
+        let mut reader = encoded.as_slice();
+        for expected in frames {
+            let frame = Frame::read(&mut reader).await.unwrap();
+            assert_eq!(frame, Some(expected));
+        }
+        assert_eq!(Frame::read(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn dropping_incoming_closes_session() {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = MuxSession::client(client);
+        drop(MuxSession::server(server));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while !client.is_closed() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("The client's session should close with the server's");
+    }
+
+    #[tokio::test]
+    async fn mux_session_works() {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = MuxSession::client(client);
+        let mut incoming = MuxSession::server(server);
+
+        // An echo server which rejects anything not on port 80
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming.recv().await {
+                tokio::spawn(async move {
+                    if incoming.request.port != 80 {
+                        incoming
+                            .reject(protocol::ErrorCode::Forbidden)
+                            .await
+                            .unwrap();
+                        return;
+                    }
+
+                    let initial_response = incoming.request.initial_plaintext.clone();
+                    let mut stream = incoming.accept(initial_response).await.unwrap();
+                    let mut buf = vec![0u8; 4096];
+                    loop {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        stream.write_all(&buf[..n]).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let request = |port| OpenRequest {
+            host: "example.com".to_string(),
+            port,
+            addresses: vec![],
+            trust_addresses: false,
+            tls: false,
+            initial_plaintext: b"hello".to_vec(),
+            initial_response_wait_ms: None,
+        };
+
+        let (response, _) = client.open(&request(81)).await.unwrap();
+        assert!(matches!(
+            response,
+            protocol::Response::Error {
+                code: protocol::ErrorCode::Forbidden,
+                ..
+            }
+        ));
+
+        // Send more than the initial window over several concurrent streams
+        let data: Vec<u8> = (0..INITIAL_WINDOW * 2).map(|i| i as u8).collect();
+        let mut tasks = vec![];
+        for _ in 0..3 {
+            let (response, stream) = client.open(&request(80)).await.unwrap();
+            assert!(matches!(
+                response,
+                protocol::Response::Success { ref initial_response, .. } if initial_response == b"hello"
+            ));
+
+            let data = data.clone();
+            tasks.push(tokio::spawn(async move {
+                let (mut r, mut w) = tokio::io::split(stream);
+                let expected = data.clone();
+                let write = async move {
+                    w.write_all(&data).await.unwrap();
+                    w.shutdown().await.unwrap();
+                };
+                let read = async move {
+                    let mut received = vec![];
+                    r.read_to_end(&mut received).await.unwrap();
+                    received
+                };
+                let (_, received) = tokio::join!(write, read);
+                assert_eq!(received, expected);
+            }));
+        }
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(!client.is_closed());
+    }
+
+    fn open_request() -> OpenRequest {
+        OpenRequest {
+            host: "example.com".to_string(),
+            port: 80,
+            addresses: vec![],
+            trust_addresses: false,
+            tls: false,
+            initial_plaintext: vec![],
+            initial_response_wait_ms: None,
+        }
+    }
+
+    fn open_frame(id: u32) -> Frame {
+        Frame::Open {
+            id,
+            request: rkyv::to_bytes::<RkyvError>(&open_request())
+                .unwrap()
+                .to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn misbehaving_peer_is_reset() {
+        let (mut peer, server) = tokio::io::duplex(INITIAL_WINDOW * 2);
+        let mut incoming = MuxSession::server(server);
+
+        // A stream that's accepted but never read, so the window isn't given back
+        peer.write_all(&open_frame(1).encode()).await.unwrap();
+        let _stream = incoming.recv().await.unwrap().accept(vec![]).await.unwrap();
+        assert!(matches!(
+            Frame::read(&mut peer).await.unwrap(),
+            Some(Frame::OpenResult { id: 1, .. })
+        ));
+
+        let data = Bytes::from(vec![0u8; INITIAL_WINDOW + 1]);
+        peer.write_all(&Frame::Data { id: 1, data }.encode())
+            .await
+            .unwrap();
+        assert_eq!(
+            Frame::read(&mut peer).await.unwrap(),
+            Some(Frame::Reset { id: 1 })
+        );
+
+        // Opening a stream that's already open resets it
+        peer.write_all(&open_frame(3).encode()).await.unwrap();
+        let pending = incoming.recv().await.unwrap();
+        peer.write_all(&open_frame(3).encode()).await.unwrap();
+        assert_eq!(
+            Frame::read(&mut peer).await.unwrap(),
+            Some(Frame::Reset { id: 3 })
+        );
+        assert!(pending.accept(vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unanswered_open_fails() {
+        let (client, server) = tokio::io::duplex(1024);
+        let client = MuxSession::client(client);
+        let mut incoming = MuxSession::server(server);
+        tokio::spawn(async move {
+            // Dropped without an answer
+            while let Some(_stream) = incoming.recv().await {}
+        });
+
+        assert!(client.open(&open_request()).await.is_err());
+        assert!(!client.is_closed());
+    }
+}
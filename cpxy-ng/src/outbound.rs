@@ -1,14 +1,17 @@
+use crate::udp::UdpTunnel;
+use anyhow::bail;
 use std::fmt::{Debug, Formatter};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 
 #[derive(Debug, Clone)]
 pub enum OutboundHost {
     Domain(String),
+    /// A domain and everything it resolved to, which is empty if the lookup failed
     Resolved {
         domain: String,
-        ip: Option<Ipv4Addr>,
+        ips: Vec<IpAddr>,
     },
 }
 
@@ -19,6 +22,14 @@ impl OutboundHost {
             OutboundHost::Resolved { domain: d, .. } => d.as_str(),
         }
     }
+
+    /// The address routing decisions are made on: the first one the host resolved to.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            OutboundHost::Domain(_) => None,
+            OutboundHost::Resolved { ips, .. } => ips.first().copied(),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -48,6 +59,11 @@ pub trait Outbound {
         &self,
         req: OutboundRequest,
     ) -> impl Future<Output = anyhow::Result<impl AsyncRead + AsyncWrite + Send + Unpin + 'static>> + Send;
+
+    /// Starts a UDP association, where each datagram carries its own destination.
+    fn send_udp(&self) -> impl Future<Output = anyhow::Result<UdpTunnel>> + Send {
+        async { bail!("UDP is not supported by this outbound") }
+    }
 }
 
 impl<O: Outbound> Outbound for Arc<O> {
@@ -58,4 +74,8 @@ impl<O: Outbound> Outbound for Arc<O> {
     {
         self.as_ref().send(req)
     }
+
+    fn send_udp(&self) -> impl Future<Output = anyhow::Result<UdpTunnel>> + Send {
+        self.as_ref().send_udp()
+    }
 }
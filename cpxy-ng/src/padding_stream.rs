@@ -0,0 +1,188 @@
+use bytes::{Buf, BufMut, BytesMut};
+use rand::{RngCore, random_range};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const HEADER_LEN: usize = 4;
+const MAX_DATA_LEN: usize = 16 * 1024;
+
+/// Pads the first `packets` writes in each direction with a random amount of filler, so the
+/// sizes of the early packets don't give away what's being carried. After that data passes
+/// through as is. Both ends must agree on the parameters.
+///
+/// A padded packet is: data length (u16), padding length (u16), data, random padding. Only the
+/// cipher underneath hides the framing, so it must encrypt the whole stream, not just its
+/// start.
+pub struct PaddedStream<S> {
+    inner: S,
+    max_padding_len: u16,
+    read_packets_left: u32,
+    write_packets_left: u32,
+    read_buf: BytesMut,
+    // Data taken out of padded packets, not yet read
+    unpadded: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl<S> PaddedStream<S> {
+    pub fn new(inner: S, packets: u32, max_padding_len: u16) -> Self {
+        let packets = if max_padding_len == 0 { 0 } else { packets };
+        Self {
+            inner,
+            max_padding_len,
+            read_packets_left: packets,
+            write_packets_left: packets,
+            read_buf: BytesMut::new(),
+            unpadded: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> PaddedStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PaddedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.unpadded.is_empty() {
+                let n = this.unpadded.len().min(buf.remaining());
+                buf.put_slice(&this.unpadded.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.read_packets_left == 0 {
+                // Whatever came in after the last padded packet goes out first
+                if !this.read_buf.is_empty() {
+                    let n = this.read_buf.len().min(buf.remaining());
+                    buf.put_slice(&this.read_buf.split_to(n));
+                    return Poll::Ready(Ok(()));
+                }
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+
+            if this.read_buf.len() >= HEADER_LEN {
+                let data_len = u16::from_be_bytes([this.read_buf[0], this.read_buf[1]]) as usize;
+                let padding_len = u16::from_be_bytes([this.read_buf[2], this.read_buf[3]]) as usize;
+
+                if this.read_buf.len() >= HEADER_LEN + data_len + padding_len {
+                    this.read_buf.advance(HEADER_LEN);
+                    this.unpadded.put(this.read_buf.split_to(data_len));
+                    this.read_buf.advance(padding_len);
+                    this.read_packets_left -= 1;
+                    continue;
+                }
+            }
+
+            let mut tmp = [0u8; 8192];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+            if tmp_buf.filled().is_empty() {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+            this.read_buf.extend_from_slice(tmp_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PaddedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+
+        if this.write_packets_left == 0 || buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Once padded the data counts as written, it goes out on the next write or flush
+        let n = buf.len().min(MAX_DATA_LEN);
+        let padding_len = random_range(0..=this.max_padding_len);
+        this.write_buf.put_u16(n as u16);
+        this.write_buf.put_u16(padding_len);
+        this.write_buf.put_slice(&buf[..n]);
+        let padding_start = this.write_buf.len();
+        this.write_buf.put_bytes(0, padding_len as usize);
+        rand::rng().fill_bytes(&mut this.write_buf[padding_start..]);
+        this.write_packets_left -= 1;
+
+        let _ = this.poll_drain(cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn padded_stream_works() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = PaddedStream::new(client, 3, 100);
+        let mut server = PaddedStream::new(server, 3, 100);
+
+        let messages: Vec<Vec<u8>> = (1..10u8).map(|i| vec![i; i as usize * 10]).collect();
+        let expected = messages.concat();
+
+        let write = async move {
+            for message in messages {
+                client.write_all(&message).await.unwrap();
+                client.flush().await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+        };
+
+        let read = async move {
+            let mut received = vec![];
+            // Smaller than most of the packets, so they have to be handed out in parts
+            let mut buf = [0u8; 7];
+            loop {
+                let n = server.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(received, expected);
+        };
+
+        tokio::join!(write, read);
+    }
+}
@@ -1,28 +1,69 @@
 use crate::encrypt_stream::Configuration;
+use crate::key_util::{KeyHint, key_hint, random_vec};
 use anyhow::{Context, ensure, format_err};
 use base64::Engine;
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use chacha20poly1305::aead::{Aead, OsRng};
 use chacha20poly1305::{AeadCore, Key, KeyInit, XChaCha20Poly1305};
+use rand::random_range;
 use rkyv::rancor::Error as RkyvError;
 use rkyv::{Archive, Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum TunnelMode {
+    /// The connection carries a single stream to `host:port`
+    Stream,
+    /// The connection carries a mux session, the target fields are unused
+    Mux,
+    /// The connection carries framed datagrams for a UDP association, the target fields are unused
+    Udp,
+}
 
 #[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub struct Request {
+    pub mode: TunnelMode,
     pub host: String,
     pub port: u16,
+    /// Addresses the client already resolved `host` to, so the server can skip the lookup
+    pub addresses: Vec<IpAddr>,
+    /// Whether the server should connect only to `addresses`. Otherwise it falls back to
+    /// resolving `host` itself when none of them can be reached.
+    pub trust_addresses: bool,
     pub tls: bool,
     pub client_send_cipher: Configuration,
     pub server_send_cipher: Configuration,
     pub initial_plaintext: Vec<u8>,
+    /// How long the server should wait for upstream's first bytes to send back with the
+    /// response: `Some(0)` replies right away, `None` leaves it to the server
+    pub initial_response_wait_ms: Option<u32>,
     pub timestamp_epoch_seconds: u64,
+    pub nonce: [u8; 16],
+    /// Whether the stream after the upgrade is carried in real WebSocket frames
+    pub websocket_framing: bool,
+    /// The client's ephemeral X25519 public key, if the stream ciphers are to be derived
+    /// from a key exchange rather than taken from this request. This request itself, and
+    /// `initial_plaintext` with it, stays sealed with the pre-shared key.
+    pub client_ephemeral_key: Option<[u8; 32]>,
+    /// Filler so the size of the request doesn't follow the target and initial data
+    pub padding: Vec<u8>,
+    /// The most padding to add to the response and to each padded packet of the stream
+    pub max_padding_len: u16,
+    /// How many packets in each direction of the stream are padded
+    pub padded_packets: u32,
+}
+
+pub fn random_padding(max_len: u16) -> Vec<u8> {
+    random_vec(random_range(0..=max_len) as usize)
 }
 
 fn secret_box_encrypt(key: &Key, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
     let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
     let cipher = chacha20poly1305::XChaCha20Poly1305::new(key);
     let mut ciphertext = cipher
-        .encrypt((&nonce).into(), plaintext)
+        .encrypt(&nonce, plaintext)
         .map_err(|e| format_err!("Error encrypting data: {e}"))?;
 
     // Prepend nonce to the ciphertext
@@ -44,28 +85,34 @@ fn secret_box_decrypt(key: &Key, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
 }
 
 impl Request {
+    /// Encrypts and encodes the request as URL-safe base64, for the camouflage to lay out.
     pub fn serialize(&self, encrypt_key: &Key) -> anyhow::Result<String> {
         let bytes = rkyv::to_bytes::<RkyvError>(self).context("Error serializing request")?;
-        let bytes = secret_box_encrypt(encrypt_key, &bytes)?;
-
-        let mut url = URL_SAFE_NO_PAD.encode(bytes);
-        let mut pos = 1;
-        while pos < url.len() - 2 {
-            let insertion_point = rand::random_range(pos..url.len());
-            url.insert(insertion_point, '/');
-            pos = insertion_point + 2;
-        }
+        let mut bytes = secret_box_encrypt(encrypt_key, &bytes)?;
+
+        // The hint goes in front in clear so the server knows which key to decrypt with
+        bytes.splice(0..0, key_hint(encrypt_key));
 
-        Ok(url)
+        Ok(URL_SAFE_NO_PAD.encode(bytes))
     }
 
-    pub fn deserialize(text: &str, encrypt_key: &Key) -> anyhow::Result<Self> {
-        let url = text.replace('/', "");
-        let bytes = URL_SAFE_NO_PAD.decode(url).map_err(|e| {
-            format_err!("Error base64 decoding request from URL path segments: {e}")
-        })?;
+    /// Deserializes a request, using `find_key` to look up the key it was encrypted with.
+    pub fn deserialize(
+        text: &str,
+        find_key: impl FnOnce(&KeyHint) -> Option<Key>,
+    ) -> anyhow::Result<Self> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(text)
+            .map_err(|e| format_err!("Error base64 decoding request: {e}"))?;
 
-        let bytes = secret_box_decrypt(encrypt_key, &bytes)?;
+        ensure!(
+            bytes.len() >= size_of::<KeyHint>(),
+            "Request too short to contain key hint"
+        );
+        let (hint, bytes) = bytes.split_at(size_of::<KeyHint>());
+        let encrypt_key = find_key(hint.try_into().unwrap()).context("Unknown key hint")?;
+
+        let bytes = secret_box_decrypt(&encrypt_key, bytes)?;
         rkyv::from_bytes::<Self, RkyvError>(&bytes).context("Error deserializing request")
     }
 }
@@ -75,14 +122,70 @@ pub enum Response {
     Success {
         initial_response: Vec<u8>,
         timestamp_epoch_seconds: u64,
+        /// The server's ephemeral X25519 public key, in answer to the client's
+        server_ephemeral_key: Option<[u8; 32]>,
+        padding: Vec<u8>,
     },
 
     Error {
-        msg: String,
+        code: ErrorCode,
         timestamp_epoch_seconds: u64,
+        padding: Vec<u8>,
     },
 }
 
+/// Why the server couldn't reach the destination. Deliberately coarse so nothing about the
+/// server's internals goes back to the client.
+#[derive(Archive, Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum ErrorCode {
+    HostNotFound,
+    ConnectionRefused,
+    TimedOut,
+    NetworkUnreachable,
+    Forbidden,
+    TlsFailure,
+    Other,
+}
+
+impl ErrorCode {
+    /// Classifies an error, either by an `ErrorCode` attached as context or by the I/O error
+    /// behind it.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(code) = err.downcast_ref::<ErrorCode>() {
+            return *code;
+        }
+
+        err.chain()
+            .filter_map(|e| e.downcast_ref::<std::io::Error>())
+            .find_map(|e| match e.kind() {
+                ErrorKind::ConnectionRefused => Some(Self::ConnectionRefused),
+                ErrorKind::TimedOut => Some(Self::TimedOut),
+                ErrorKind::NetworkUnreachable | ErrorKind::HostUnreachable => {
+                    Some(Self::NetworkUnreachable)
+                }
+                ErrorKind::PermissionDenied => Some(Self::Forbidden),
+                _ => None,
+            })
+            .unwrap_or(Self::Other)
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::HostNotFound => "host not found",
+            Self::ConnectionRefused => "connection refused",
+            Self::TimedOut => "timed out",
+            Self::NetworkUnreachable => "network unreachable",
+            Self::Forbidden => "destination forbidden",
+            Self::TlsFailure => "TLS handshake failed",
+            Self::Other => "upstream error",
+        })
+    }
+}
+
+impl std::error::Error for ErrorCode {}
+
 impl Response {
     pub fn serialize(&self, encrypt_key: &Key) -> anyhow::Result<Vec<u8>> {
         let bytes = rkyv::to_bytes::<RkyvError>(self).context("Error serializing response")?;
@@ -98,26 +201,42 @@ impl Response {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::key_util::key_hint_matches;
     use chacha20poly1305::ChaCha20Poly1305;
 
     #[test]
     fn test_request_serialization() {
         let request = Request {
+            mode: TunnelMode::Stream,
             host: "example.com".to_string(),
             port: 8080,
             client_send_cipher: Configuration::random_full(),
             server_send_cipher: Configuration::random_full(),
             initial_plaintext: b"Hello, World!".to_vec(),
+            initial_response_wait_ms: Some(100),
             timestamp_epoch_seconds: 0,
+            nonce: [1u8; 16],
+            websocket_framing: false,
+            client_ephemeral_key: None,
+            padding: random_padding(100),
+            max_padding_len: 100,
+            padded_packets: 8,
+            addresses: vec!["1.2.3.4".parse().unwrap(), "::1".parse().unwrap()],
+            trust_addresses: true,
             tls: false,
         };
 
         let key = ChaCha20Poly1305::generate_key(&mut OsRng);
 
         let url_path = request.serialize(&key).unwrap();
-        let deserialized_request = Request::deserialize(&url_path, &key).unwrap();
+        let deserialized_request = Request::deserialize(&url_path, |_| Some(key)).unwrap();
 
         assert_eq!(request, deserialized_request);
+
+        let other_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let find_other_key =
+            |hint: &KeyHint| key_hint_matches(&other_key, hint).then_some(other_key);
+        assert!(Request::deserialize(&url_path, find_other_key).is_err());
     }
 
     #[test]
@@ -125,6 +244,8 @@ mod tests {
         let response = Response::Success {
             timestamp_epoch_seconds: 0,
             initial_response: b"Hello, Client!".to_vec(),
+            server_ephemeral_key: Some([3u8; 32]),
+            padding: vec![0u8; 10],
         };
 
         let key = ChaCha20Poly1305::generate_key(&mut OsRng);
@@ -134,4 +255,18 @@ mod tests {
 
         assert_eq!(response, deserialized_response);
     }
+
+    #[test]
+    fn error_code_classification_works() {
+        let err = anyhow::Error::from(std::io::Error::from(ErrorKind::ConnectionRefused))
+            .context("Error connecting to upstream");
+        assert_eq!(ErrorCode::of(&err), ErrorCode::ConnectionRefused);
+
+        let err = anyhow::Error::from(std::io::Error::other("lookup failed"))
+            .context(ErrorCode::HostNotFound)
+            .context("Error connecting to upstream");
+        assert_eq!(ErrorCode::of(&err), ErrorCode::HostNotFound);
+
+        assert_eq!(ErrorCode::of(&anyhow::anyhow!("nope")), ErrorCode::Other);
+    }
 }
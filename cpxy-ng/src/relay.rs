@@ -0,0 +1,157 @@
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, sleep_until};
+
+/// What went through a stream, and when it last did.
+struct Activity {
+    started: Instant,
+    last_millis: AtomicU64,
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl Activity {
+    fn record(&self, counter: &AtomicU64, n: usize) {
+        if n > 0 {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+            self.last_millis
+                .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn last(&self) -> Instant {
+        self.started + Duration::from_millis(self.last_millis.load(Ordering::Relaxed))
+    }
+}
+
+struct Tracked<'a, S> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        this.activity
+            .record(&this.activity.read, buf.filled().len() - filled);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &result {
+            this.activity.record(&this.activity.written, *n);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Like [`tokio::io::copy_bidirectional`], but also stops once no bytes have flowed in
+/// either direction for `idle_timeout`, leaving both streams for the caller to drop. However
+/// it ends, gives the bytes copied from `a` to `b` and from `b` to `a`, along with the error
+/// that ended it if any.
+pub async fn copy_bidirectional_with_idle_timeout<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle_timeout: Duration,
+) -> ((u64, u64), std::io::Result<()>)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity {
+        started: Instant::now(),
+        last_millis: AtomicU64::new(0),
+        read: AtomicU64::new(0),
+        written: AtomicU64::new(0),
+    };
+
+    // Everything relayed is either read from or written to `a`, so watching it is enough
+    let mut tracked = Tracked {
+        inner: a,
+        activity: &activity,
+    };
+
+    let idle = async {
+        loop {
+            let deadline = activity.last() + idle_timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    };
+
+    let result = tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut tracked, b) => result.map(|_| ()),
+        _ = idle => Ok(()),
+    };
+
+    (
+        (
+            activity.read.load(Ordering::Relaxed),
+            activity.written.load(Ordering::Relaxed),
+        ),
+        result,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn idle_relay_stops() {
+        let (mut a, mut a_peer) = tokio::io::duplex(64);
+        let (mut b, mut b_peer) = tokio::io::duplex(64);
+
+        let relay = tokio::spawn(async move {
+            copy_bidirectional_with_idle_timeout(&mut a, &mut b, Duration::from_millis(300)).await
+        });
+
+        // Traffic keeps the relay going past the idle timeout
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            a_peer.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            b_peer.read_exact(&mut buf).await.unwrap();
+            b_peer.write_all(b"pong!").await.unwrap();
+            a_peer.read_exact(&mut [0u8; 5]).await.unwrap();
+        }
+        assert!(!relay.is_finished());
+
+        let (relayed, result) = relay.await.unwrap();
+        result.unwrap();
+        assert_eq!(relayed, (12, 15));
+
+        // Both sides are closed once the relay lets go of them
+        assert_eq!(a_peer.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert_eq!(b_peer.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+}
@@ -1,12 +1,29 @@
 use crate::either_stream::EitherStream;
-use anyhow::Context;
+use anyhow::{Context, bail, ensure, format_err};
+use base64::Engine;
+use base64::prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use sha2::Digest;
+use std::borrow::Cow;
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
 use std::sync::{Arc, LazyLock};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::TlsConnector;
 use tokio_rustls::client::TlsStream;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::ParsedCertificate;
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
 
-static CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
+static DEFAULT_CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
     let mut root_cert_store = RootCertStore::empty();
     root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
 
@@ -17,13 +34,219 @@ static CONNECTOR: LazyLock<TlsConnector> = LazyLock::new(|| {
     ))
 });
 
+/// How to connect to TLS servers: what to trust, what to present and what to offer.
+/// Cheap to clone, the files involved are read once when the options are parsed.
+#[derive(Clone)]
+pub struct TlsOptions {
+    connector: TlsConnector,
+    sni: Option<String>,
+}
+
+impl Debug for TlsOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("sni", &self.sni)
+            .finish()
+    }
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self {
+            connector: DEFAULT_CONNECTOR.clone(),
+            sni: None,
+        }
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format_err!("Error reading certificates from {path:?}: {e}"))
+}
+
+fn parse_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let pin = pin.strip_prefix("sha256//").unwrap_or(pin);
+    BASE64_STANDARD
+        .decode(pin)
+        .or_else(|_| BASE64_URL_SAFE_NO_PAD.decode(pin))
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .with_context(|| format!("Expected a base64 SHA-256 hash as pin, got {pin}"))
+}
+
+impl TlsOptions {
+    /// Reads options from query pairs, ignoring the keys it doesn't know:
+    /// - `ca`: a PEM file of roots to trust on top of the built-in ones, can be repeated
+    /// - `native_roots`: whether to trust the system's roots too
+    /// - `pin`: the base64 SHA-256 of a SubjectPublicKeyInfo, can be repeated. The server's
+    ///   certificate must carry one of the pinned keys, and is then trusted without a CA,
+    ///   so self-signed certificates work. Can't be combined with `ca` or `native_roots`.
+    /// - `sni`: the name to send and verify instead of the host connected to
+    /// - `alpn`: the protocols to offer, comma separated
+    /// - `cert` and `key`: a PEM certificate chain and private key to authenticate with
+    pub fn from_query_pairs<'a>(
+        pairs: impl IntoIterator<Item = (Cow<'a, str>, Cow<'a, str>)>,
+    ) -> anyhow::Result<Self> {
+        let mut roots = vec![];
+        let mut native_roots = false;
+        let mut pins = vec![];
+        let mut sni = None;
+        let mut alpn = vec![];
+        let mut cert = None;
+        let mut key = None;
+        let mut customized = false;
+
+        for (k, v) in pairs {
+            match k.as_ref() {
+                "ca" => roots.extend(read_certs(Path::new(v.as_ref()))?),
+                "native_roots" => {
+                    native_roots = v
+                        .parse()
+                        .context("Expected native_roots to be true or false")?
+                }
+                "pin" => pins.push(parse_pin(&v)?),
+                "sni" => {
+                    ServerName::try_from(v.as_ref())
+                        .with_context(|| format!("Invalid sni: {v}"))?;
+                    sni = Some(v.into_owned());
+                }
+                "alpn" => alpn.extend(v.split(',').map(|p| p.trim().as_bytes().to_vec())),
+                "cert" => cert = Some(read_certs(Path::new(v.as_ref()))?),
+                "key" => {
+                    key = Some(
+                        PrivateKeyDer::from_pem_file(v.as_ref())
+                            .map_err(|e| format_err!("Error reading private key from {v}: {e}"))?,
+                    )
+                }
+                _ => continue,
+            }
+            customized = true;
+        }
+
+        if !customized {
+            return Ok(Self::default());
+        }
+
+        ensure!(
+            pins.is_empty() || (roots.is_empty() && !native_roots),
+            "pin can't be combined with ca or native_roots, as pinned keys are trusted without a CA"
+        );
+
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if native_roots {
+            roots.extend(read_native_roots()?);
+        }
+        let (_, ignored) = root_cert_store.add_parsable_certificates(roots);
+        ensure!(
+            ignored == 0,
+            "{ignored} of the root certificates are invalid"
+        );
+
+        let builder = ClientConfig::builder();
+        let builder = if pins.is_empty() {
+            builder.with_root_certificates(root_cert_store)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedKeyVerifier {
+                    pins,
+                    algorithms: tokio_rustls::rustls::crypto::ring::default_provider()
+                        .signature_verification_algorithms,
+                }))
+        };
+
+        let mut config = match (cert, key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(cert, key)
+                .context("Invalid client certificate or key")?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => bail!("A client certificate needs both cert and key"),
+        };
+        config.alpn_protocols = alpn;
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            sni,
+        })
+    }
+
+    /// Parses options given as a query string, such as `ca=/etc/ca.pem&alpn=h2`.
+    pub fn from_query_str(query: &str) -> anyhow::Result<Self> {
+        Self::from_query_pairs(url::form_urlencoded::parse(query.as_bytes()))
+    }
+}
+
+fn read_native_roots() -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    // Some unreadable certificates are fine, as long as the platform's store gave us any
+    let native = rustls_native_certs::load_native_certs();
+    ensure!(
+        !native.certs.is_empty(),
+        "Unable to load the system's root certificates: {:?}",
+        native.errors
+    );
+    Ok(native.certs)
+}
+
+/// Trusts a certificate for the key it carries, rather than for who signed it.
+#[derive(Debug)]
+struct PinnedKeyVerifier {
+    pins: Vec<[u8; 32]>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        let hash: [u8; 32] = sha2::Sha256::digest(cert.subject_public_key_info()).into();
+        if self.pins.contains(&hash) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(CertificateError::ApplicationVerificationFailure.into())
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
 pub async fn connect_tls<S: AsyncRead + AsyncWrite + Unpin>(
     domain_name: &str,
     tls: bool,
     inner: S,
+    options: &TlsOptions,
 ) -> anyhow::Result<EitherStream<TlsStream<S>, S>> {
     if tls {
-        CONNECTOR
+        let domain_name = options.sni.as_deref().unwrap_or(domain_name);
+        options
+            .connector
             .connect(
                 domain_name
                     .to_string()
@@ -38,3 +261,29 @@ pub async fn connect_tls<S: AsyncRead + AsyncWrite + Unpin>(
         Ok(EitherStream::Right(inner))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_options_parsing_works() {
+        assert!(TlsOptions::from_query_str("mux=true").is_ok());
+        assert!(
+            TlsOptions::from_query_str(
+                "pin=sha256//47DEQpj8HBSa%2B/TImW%2B5JCeuQeRkm5NMpJWZG3hSuFU%3D&sni=example.com&alpn=h2,http/1.1"
+            )
+            .is_ok()
+        );
+
+        assert!(TlsOptions::from_query_str("pin=abc").is_err());
+        assert!(TlsOptions::from_query_str("ca=/nonexistent.pem").is_err());
+        assert!(TlsOptions::from_query_str("sni=not a name").is_err());
+        assert!(
+            TlsOptions::from_query_str(
+                "pin=47DEQpj8HBSa%2B/TImW%2B5JCeuQeRkm5NMpJWZG3hSuFU%3D&native_roots=true"
+            )
+            .is_err()
+        );
+    }
+}
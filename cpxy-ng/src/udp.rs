@@ -0,0 +1,348 @@
+use anyhow::{Context, ensure};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{UdpSocket, lookup_host};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, sleep_until, timeout};
+
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// A datagram travelling through the tunnel. Going out `host:port` is the destination,
+/// coming back it's the source.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Datagram {
+    pub host: String,
+    pub port: u16,
+    pub payload: Bytes,
+}
+
+impl Datagram {
+    pub async fn read_from(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<Self>> {
+        let host_len = match stream.read_u8().await {
+            Ok(v) => v as usize,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e).context("Error reading datagram host length"),
+        };
+
+        let mut host = vec![0u8; host_len];
+        stream
+            .read_exact(&mut host)
+            .await
+            .context("Error reading datagram host")?;
+        let host = String::from_utf8(host).context("Invalid UTF-8 in datagram host")?;
+
+        let port = stream
+            .read_u16()
+            .await
+            .context("Error reading datagram port")?;
+
+        let len = stream
+            .read_u16()
+            .await
+            .context("Error reading datagram length")?;
+
+        let mut payload = vec![0u8; len as usize];
+        stream
+            .read_exact(&mut payload)
+            .await
+            .context("Error reading datagram payload")?;
+
+        Ok(Some(Self {
+            host,
+            port,
+            payload: payload.into(),
+        }))
+    }
+
+    pub async fn write_to(&self, stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+        ensure!(
+            self.host.len() <= u8::MAX as usize,
+            "Datagram host too long"
+        );
+        ensure!(self.payload.len() <= MAX_DATAGRAM_LEN, "Datagram too large");
+
+        let mut buf = Vec::with_capacity(1 + self.host.len() + 4 + self.payload.len());
+        buf.push(self.host.len() as u8);
+        buf.extend_from_slice(self.host.as_bytes());
+        buf.extend_from_slice(&self.port.to_be_bytes());
+        buf.extend_from_slice(&(self.payload.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+
+        stream
+            .write_all(&buf)
+            .await
+            .context("Error writing datagram")
+    }
+}
+
+/// One end of a UDP association: `tx` sends datagrams towards the other end, `rx` receives
+/// what the other end sent.
+pub struct UdpTunnel {
+    pub tx: mpsc::Sender<Datagram>,
+    pub rx: mpsc::Receiver<Datagram>,
+}
+
+impl UdpTunnel {
+    /// Creates two connected ends.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel(64);
+        let (b_tx, a_rx) = mpsc::channel(64);
+        (Self { tx: a_tx, rx: a_rx }, Self { tx: b_tx, rx: b_rx })
+    }
+
+    /// Carries the datagrams framed over a stream.
+    pub fn over_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (local, remote) = Self::pair();
+        let (mut r, mut w) = tokio::io::split(stream);
+        let UdpTunnel { tx, mut rx } = remote;
+
+        tokio::spawn(async move {
+            while let Ok(Some(datagram)) = Datagram::read_from(&mut r).await {
+                if tx.send(datagram).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            while let Some(datagram) = rx.recv().await {
+                if datagram.write_to(&mut w).await.is_err() || w.flush().await.is_err() {
+                    return;
+                }
+            }
+            let _ = w.shutdown().await;
+        });
+
+        local
+    }
+}
+
+// How long a lookup may take, and how long a failed one is remembered
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const FAILED_LOOKUP_TTL: Duration = Duration::from_secs(30);
+// How many datagrams to a host can wait for its lookup
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+enum Resolution {
+    /// Being looked up, with the payloads waiting for it
+    Pending(Vec<Bytes>),
+    Resolved(SocketAddr),
+    /// The lookup failed or the address is refused, until the given time
+    Failed(Instant),
+}
+
+enum Event {
+    Outgoing(Option<Datagram>),
+    Incoming(std::io::Result<(usize, SocketAddr)>, bool),
+    Resolved((String, u16), Option<SocketAddr>),
+}
+
+async fn recv_from(
+    socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn lookup(host: String, port: u16) -> ((String, u16), Option<SocketAddr>) {
+    let addr = match timeout(LOOKUP_TIMEOUT, lookup_host((host.as_str(), port))).await {
+        Ok(Ok(mut addrs)) => addrs.next(),
+        _ => None,
+    };
+    ((host, port), addr)
+}
+
+async fn send_to(
+    v4_socket: &mut Option<UdpSocket>,
+    v6_socket: &mut Option<UdpSocket>,
+    payload: &[u8],
+    addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let (socket, bind_addr) = if addr.is_ipv4() {
+        (v4_socket, "0.0.0.0:0")
+    } else {
+        (v6_socket, "[::]:0")
+    };
+
+    if socket.is_none() {
+        *socket = Some(
+            UdpSocket::bind(bind_addr)
+                .await
+                .context("Error binding UDP socket")?,
+        );
+    }
+
+    if let Some(socket) = socket {
+        let _ = socket.send_to(payload, addr).await;
+    }
+    Ok(())
+}
+
+/// Sends the datagrams from the tunnel out of local UDP sockets and relays the replies back,
+/// until either side goes away or nothing has been sent or received for `idle_timeout`.
+/// Datagrams to a host and address that `allow` refuses are dropped.
+pub async fn relay_to_socket(
+    tunnel: UdpTunnel,
+    idle_timeout: Duration,
+    allow: impl Fn(&str, SocketAddr) -> bool,
+) -> anyhow::Result<()> {
+    let UdpTunnel { tx, mut rx } = tunnel;
+
+    // Sockets are bound on first use as not every host has both IPv4 and IPv6
+    let mut v4_socket: Option<UdpSocket> = None;
+    let mut v6_socket: Option<UdpSocket> = None;
+    let mut resolved: HashMap<(String, u16), Resolution> = HashMap::new();
+    // Looked up aside, so a slow lookup holds up nothing but the datagrams waiting for it
+    let mut lookups = JoinSet::new();
+    let mut v4_buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let mut v6_buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let mut deadline = Instant::now() + idle_timeout;
+
+    loop {
+        let event = select! {
+            datagram = rx.recv() => Event::Outgoing(datagram),
+            r = recv_from(&v4_socket, &mut v4_buf) => Event::Incoming(r, false),
+            r = recv_from(&v6_socket, &mut v6_buf) => Event::Incoming(r, true),
+            Some(r) = lookups.join_next(), if !lookups.is_empty() => {
+                let (key, addr) = r.context("Error looking up UDP destination")?;
+                Event::Resolved(key, addr)
+            }
+            _ = sleep_until(deadline) => return Ok(()),
+        };
+
+        match event {
+            Event::Outgoing(None) => return Ok(()),
+
+            Event::Outgoing(Some(Datagram {
+                host,
+                port,
+                payload,
+            })) => {
+                // Like any UDP path, datagrams that can't be delivered are simply dropped
+                let key = (host, port);
+                match resolved.get_mut(&key) {
+                    Some(Resolution::Resolved(addr)) => {
+                        send_to(&mut v4_socket, &mut v6_socket, &payload, *addr).await?;
+                    }
+                    Some(Resolution::Pending(waiting)) => {
+                        if waiting.len() < MAX_PENDING_DATAGRAMS {
+                            waiting.push(payload);
+                        }
+                    }
+                    Some(Resolution::Failed(until)) if Instant::now() < *until => {}
+                    _ => {
+                        lookups.spawn(lookup(key.0.clone(), key.1));
+                        resolved.insert(key, Resolution::Pending(vec![payload]));
+                    }
+                }
+            }
+
+            Event::Incoming(r, is_v6) => {
+                let (n, from) = r.context("Error receiving from UDP socket")?;
+                let buf = if is_v6 { &v6_buf } else { &v4_buf };
+                let datagram = Datagram {
+                    host: from.ip().to_string(),
+                    port: from.port(),
+                    payload: Bytes::copy_from_slice(&buf[..n]),
+                };
+
+                if tx.send(datagram).await.is_err() {
+                    return Ok(());
+                }
+            }
+
+            Event::Resolved(key, addr) => {
+                let addr = addr.filter(|addr| allow(&key.0, *addr));
+                let resolution = match addr {
+                    Some(addr) => Resolution::Resolved(addr),
+                    None => Resolution::Failed(Instant::now() + FAILED_LOOKUP_TTL),
+                };
+
+                if let Some(Resolution::Pending(waiting)) = resolved.insert(key, resolution)
+                    && let Some(addr) = addr
+                {
+                    for payload in waiting {
+                        send_to(&mut v4_socket, &mut v6_socket, &payload, addr).await?;
+                    }
+                }
+                continue;
+            }
+        }
+
+        deadline = Instant::now() + idle_timeout;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn datagram_framing_works() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = UdpTunnel::over_stream(client);
+        let mut server = UdpTunnel::over_stream(server);
+
+        let datagram = Datagram {
+            host: "example.com".to_string(),
+            port: 53,
+            payload: Bytes::from_static(b"query"),
+        };
+
+        client.tx.send(datagram.clone()).await.unwrap();
+        assert_eq!(server.rx.recv().await, Some(datagram.clone()));
+
+        server.tx.send(datagram.clone()).await.unwrap();
+        assert_eq!(client.rx.recv().await, Some(datagram));
+    }
+
+    #[tokio::test]
+    async fn relay_to_socket_works() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], from).await.unwrap();
+            }
+        });
+
+        let (mut local, remote) = UdpTunnel::pair();
+        let relay = tokio::spawn(relay_to_socket(
+            remote,
+            Duration::from_millis(200),
+            |_, _| true,
+        ));
+
+        local
+            .tx
+            .send(Datagram {
+                host: "127.0.0.1".to_string(),
+                port: echo_addr.port(),
+                payload: Bytes::from_static(b"ping"),
+            })
+            .await
+            .unwrap();
+
+        let reply = local.rx.recv().await.unwrap();
+        assert_eq!(reply.port, echo_addr.port());
+        assert_eq!(reply.payload.as_ref(), b"ping");
+
+        // The relay should give up once idle
+        relay.await.unwrap().unwrap();
+        assert_eq!(local.rx.recv().await, None);
+    }
+}
@@ -0,0 +1,419 @@
+use bytes::{Buf, BufMut, BytesMut};
+use rand::random;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep, sleep};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const MAX_WRITE_PAYLOAD_LEN: usize = 16 * 1024;
+const MAX_CONTROL_PAYLOAD_LEN: usize = 125;
+// Well within the idle cutoffs of CDNs and reverse proxies, which are commonly 60-100s
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+struct FrameHeader {
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    payload_len: u64,
+    header_len: usize,
+}
+
+impl FrameHeader {
+    fn parse(buf: &[u8]) -> std::io::Result<Option<Self>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let opcode = buf[0] & 0x0F;
+        let masked = buf[1] & 0x80 != 0;
+        let (payload_len, mut header_len) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+
+        let mask = if masked {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mask = buf[header_len..header_len + 4].try_into().unwrap();
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        if opcode >= OPCODE_CLOSE && payload_len > MAX_CONTROL_PAYLOAD_LEN as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "WebSocket control frame too large",
+            ));
+        }
+
+        Ok(Some(Self {
+            opcode,
+            mask,
+            payload_len,
+            header_len,
+        }))
+    }
+}
+
+fn encode_frame(out: &mut BytesMut, role: Role, opcode: u8, payload: &[u8]) {
+    out.put_u8(0x80 | opcode);
+
+    // Only clients mask, as RFC 6455 requires
+    let mask_bit = if role == Role::Client { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.put_u8(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            out.put_u8(mask_bit | 126);
+            out.put_u16(len as u16);
+        }
+        len => {
+            out.put_u8(mask_bit | 127);
+            out.put_u64(len as u64);
+        }
+    }
+
+    if role == Role::Client {
+        let mask: [u8; 4] = random();
+        out.put_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        out.put_slice(payload);
+    }
+}
+
+/// Carries a byte stream as binary WebSocket messages on top of an upgraded connection.
+/// Pings are answered, and sent every 30 seconds while reading to keep idle tunnels
+/// open. A close frame from the peer ends the read side and, as WebSocket has no half-close,
+/// the write side too. Frames masked the wrong way for the peer's role are an error.
+pub struct WebSocketStream<S> {
+    inner: S,
+    role: Role,
+    read_buf: BytesMut,
+    // The data frame being read: remaining payload bytes, mask and the position within it
+    frame_remaining: u64,
+    frame_mask: Option<[u8; 4]>,
+    frame_mask_offset: usize,
+    read_closed: bool,
+    write_buf: BytesMut,
+    close_sent: bool,
+    ping_interval: Duration,
+    // Started on the first read
+    ping: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> WebSocketStream<S> {
+    pub fn new(inner: S, role: Role) -> Self {
+        Self {
+            inner,
+            role,
+            read_buf: BytesMut::new(),
+            frame_remaining: 0,
+            frame_mask: None,
+            frame_mask_offset: 0,
+            read_closed: false,
+            write_buf: BytesMut::new(),
+            close_sent: false,
+            ping_interval: PING_INTERVAL,
+            ping: None,
+        }
+    }
+
+    fn poll_ping(&mut self, cx: &mut Context<'_>) {
+        let interval = self.ping_interval;
+        let ping = self.ping.get_or_insert_with(|| Box::pin(sleep(interval)));
+        while ping.as_mut().poll(cx).is_ready() {
+            ping.as_mut().reset(Instant::now() + interval);
+            if !self.close_sent {
+                encode_frame(&mut self.write_buf, self.role, OPCODE_PING, &[]);
+            }
+        }
+    }
+
+    fn queue_close(&mut self) {
+        if !self.close_sent {
+            self.close_sent = true;
+            // Status 1000: normal closure
+            encode_frame(
+                &mut self.write_buf,
+                self.role,
+                OPCODE_CLOSE,
+                &1000u16.to_be_bytes(),
+            );
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> WebSocketStream<S> {
+    fn poll_drain_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_ping(cx);
+
+        loop {
+            // Get pings, pongs and close replies out of the door. If the writer is busy whoever
+            // writes or flushes next picks them up.
+            if let Poll::Ready(Err(e)) = this.poll_drain_write_buf(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.frame_remaining > 0 && !this.read_buf.is_empty() {
+                let n = (this.frame_remaining.min(this.read_buf.len() as u64) as usize)
+                    .min(buf.remaining());
+                let mut data = this.read_buf.split_to(n);
+                if let Some(mask) = this.frame_mask {
+                    for b in data.iter_mut() {
+                        *b ^= mask[this.frame_mask_offset % 4];
+                        this.frame_mask_offset += 1;
+                    }
+                }
+                buf.put_slice(&data);
+                this.frame_remaining -= n as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.frame_remaining == 0
+                && let Some(header) = FrameHeader::parse(&this.read_buf)?
+            {
+                // Clients must mask every frame and servers must not, RFC 6455 section 5.1
+                if header.mask.is_some() != (this.role == Role::Server) {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "WebSocket frame masked the wrong way",
+                    )));
+                }
+
+                match header.opcode {
+                    OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                        this.read_buf.advance(header.header_len);
+                        this.frame_remaining = header.payload_len;
+                        this.frame_mask = header.mask;
+                        this.frame_mask_offset = 0;
+                        continue;
+                    }
+
+                    OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                        let frame_len = header.header_len + header.payload_len as usize;
+                        if this.read_buf.len() >= frame_len {
+                            let mut frame = this.read_buf.split_to(frame_len);
+                            let payload = &mut frame[header.header_len..];
+                            if let Some(mask) = header.mask {
+                                for (i, b) in payload.iter_mut().enumerate() {
+                                    *b ^= mask[i % 4];
+                                }
+                            }
+
+                            match header.opcode {
+                                OPCODE_PING => encode_frame(
+                                    &mut this.write_buf,
+                                    this.role,
+                                    OPCODE_PONG,
+                                    payload,
+                                ),
+                                OPCODE_CLOSE => {
+                                    this.queue_close();
+                                    this.read_closed = true;
+                                }
+                                _ => {}
+                            }
+                            continue;
+                        }
+                    }
+
+                    opcode => {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Unknown WebSocket opcode {opcode}"),
+                        )));
+                    }
+                }
+            }
+
+            // Need more data from the underlying stream
+            let mut tmp = [0u8; 8192];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+            if tmp_buf.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.read_buf.extend_from_slice(tmp_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain_write_buf(cx))?;
+
+        // Nothing may follow a close frame, whichever side sent it first
+        if this.close_sent {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "WebSocket connection is closing",
+            )));
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Once framed the data counts as written, it goes out on the next write or flush
+        let n = buf.len().min(MAX_WRITE_PAYLOAD_LEN);
+        encode_frame(&mut this.write_buf, this.role, OPCODE_BINARY, &buf[..n]);
+        let _ = this.poll_drain_write_buf(cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        this.queue_close();
+        ready!(this.poll_drain_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn websocket_stream_works() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = WebSocketStream::new(client, Role::Client);
+        let mut server = WebSocketStream::new(server, Role::Server);
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let write = async move {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+            client
+        };
+
+        let read = async move {
+            let mut received = vec![];
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+        };
+
+        tokio::join!(write, read);
+    }
+
+    #[tokio::test]
+    async fn websocket_ping_is_answered() {
+        let (mut raw, server) = tokio::io::duplex(1024);
+        let mut server = WebSocketStream::new(server, Role::Server);
+
+        // A masked ping followed by a masked binary frame, as a client would send
+        let mut frames = BytesMut::new();
+        encode_frame(&mut frames, Role::Client, OPCODE_PING, b"hi");
+        encode_frame(&mut frames, Role::Client, OPCODE_BINARY, b"data");
+        raw.write_all(&frames).await.unwrap();
+
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+
+        let mut pong = [0u8; 4];
+        raw.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x80 | OPCODE_PONG, 2, b'h', b'i']);
+    }
+
+    #[tokio::test]
+    async fn websocket_enforces_masking() {
+        let (mut raw, server) = tokio::io::duplex(1024);
+        let mut server = WebSocketStream::new(server, Role::Server);
+
+        let mut frames = BytesMut::new();
+        encode_frame(&mut frames, Role::Server, OPCODE_BINARY, b"data");
+        raw.write_all(&frames).await.unwrap();
+        assert!(server.read(&mut [0u8; 4]).await.is_err());
+
+        let (mut raw, client) = tokio::io::duplex(1024);
+        let mut client = WebSocketStream::new(client, Role::Client);
+        let mut frames = BytesMut::new();
+        encode_frame(&mut frames, Role::Client, OPCODE_BINARY, b"data");
+        raw.write_all(&frames).await.unwrap();
+        assert!(client.read(&mut [0u8; 4]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn websocket_close_ends_writes() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client = WebSocketStream::new(client, Role::Client);
+        let mut server = WebSocketStream::new(server, Role::Server);
+
+        client.shutdown().await.unwrap();
+        assert!(client.write_all(b"late").await.is_err());
+
+        // The peer's close is answered, after which it can't write either
+        assert_eq!(server.read(&mut [0u8; 4]).await.unwrap(), 0);
+        assert!(server.write_all(b"late").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn websocket_sends_pings() {
+        let (mut raw, server) = tokio::io::duplex(1024);
+        let mut server = WebSocketStream::new(server, Role::Server);
+        server.ping_interval = Duration::from_millis(50);
+        tokio::spawn(async move {
+            let _ = server.read(&mut [0u8; 4]).await;
+        });
+
+        let mut ping = [0u8; 4];
+        raw.read_exact(&mut ping).await.unwrap();
+        assert_eq!(ping, [0x80 | OPCODE_PING, 0, 0x80 | OPCODE_PING, 0]);
+    }
+}
@@ -1,16 +1,18 @@
-use cpxy_ng::geoip::{GeoIPv4Entry, serialize_entries};
+use cpxy_ng::geoip::{GeoIPv4Entry, GeoIPv6Entry, serialize_entries};
 use geoip_v2ray::proto::GeoIp;
-use ipnet::Ipv4Net;
+use ipnet::{Ipv4Net, Ipv6Net};
 use prost::Message;
 use reqwest::blocking::get;
 use std::fs::File;
 use std::io::Read;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::Path;
 
 fn main() {
-    let output_file = Path::new(std::env::var("OUT_DIR").unwrap().as_str()).join("geoip.dat");
-    if output_file.exists() {
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let v4_file = Path::new(out_dir.as_str()).join("geoip.dat");
+    let v6_file = Path::new(out_dir.as_str()).join("geoip6.dat");
+    if v4_file.exists() && v6_file.exists() {
         return;
     }
 
@@ -21,38 +23,53 @@ fn main() {
     resp.read_to_end(&mut buf).expect("To read all files");
 
     let list = geoip_v2ray::proto::GeoIpList::decode(buf.as_slice()).expect("To deserialize data");
-    let entries: Vec<_> = list
+    let cidrs: Vec<_> = list
         .entry
         .into_iter()
         .filter(|i| {
             i.country_code.eq_ignore_ascii_case("china")
                 || i.country_code.eq_ignore_ascii_case("cn")
         })
-        .flat_map(|GeoIp { cidr, .. }| {
-            let country_code = *b"CN";
+        .flat_map(|GeoIp { cidr, .. }| cidr)
+        .collect();
 
-            cidr.into_iter()
-                .filter_map(|cidr| {
-                    if cidr.ip.len() == 4 {
-                        Some((
-                            Ipv4Addr::from([cidr.ip[0], cidr.ip[1], cidr.ip[2], cidr.ip[3]]),
-                            cidr.prefix,
-                        ))
-                    } else {
-                        None
-                    }
-                })
-                .map(move |(addr, prefix)| {
-                    let net = Ipv4Net::new(addr, prefix as u8).unwrap();
-                    GeoIPv4Entry::new(addr, net.broadcast(), country_code)
-                })
+    let country_code = *b"CN";
+    let v4_entries: Vec<_> = cidrs
+        .iter()
+        .filter_map(|cidr| {
+            let ip: [u8; 4] = cidr.ip.as_slice().try_into().ok()?;
+            let net = Ipv4Net::new(Ipv4Addr::from(ip), cidr.prefix as u8).ok()?;
+            Some(GeoIPv4Entry::new(
+                net.network(),
+                net.broadcast(),
+                country_code,
+            ))
+        })
+        .collect();
+    let v6_entries: Vec<_> = cidrs
+        .iter()
+        .filter_map(|cidr| {
+            let ip: [u8; 16] = cidr.ip.as_slice().try_into().ok()?;
+            let net = Ipv6Net::new(Ipv6Addr::from(ip), cidr.prefix as u8).ok()?;
+            Some(GeoIPv6Entry::new(
+                net.network(),
+                net.broadcast(),
+                country_code,
+            ))
         })
         .collect();
 
-    let mut file = File::options()
+    serialize_entries(create_file(&v4_file), v4_entries)
+        .expect("Could not serialize GeoIP archive");
+    serialize_entries(create_file(&v6_file), v6_entries)
+        .expect("Could not serialize GeoIPv6 archive");
+}
+
+fn create_file(path: &Path) -> File {
+    File::options()
         .create(true)
         .write(true)
-        .open(output_file)
-        .expect("Could not create GeoIP archive");
-    serialize_entries(&mut file, entries).expect("Could not serialize GeoIP archive");
+        .truncate(true)
+        .open(path)
+        .expect("Could not create GeoIP archive")
 }
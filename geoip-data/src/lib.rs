@@ -1 +1,2 @@
-pub static CN_GEOIP: &'static [u8] = include_bytes!(concat!(env!("OUT_DIR"), "/geoip.dat"));
+pub static CN_GEOIP: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/geoip.dat"));
+pub static CN_GEOIP_V6: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/geoip6.dat"));
@@ -0,0 +1,401 @@
+use crate::policy::PortRange;
+use anyhow::{Context, ensure};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Settings shared by every listener.
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServerSettings {
+    /// The maximum allowed difference between the client's and the server's clock, in
+    /// seconds. Defaults to 120
+    #[clap(long, env)]
+    pub max_clock_skew_secs: Option<u64>,
+
+    /// The maximum number of recently seen request nonces to remember for replay protection.
+    /// Requests are refused while it's full. Defaults to 100000
+    #[clap(long, env)]
+    pub replay_cache_size: Option<usize>,
+
+    /// Where to serve Prometheus metrics at /metrics, e.g. 127.0.0.1:9100. Off by default
+    #[clap(long, env)]
+    pub metrics_addr: Option<String>,
+
+    /// On SIGTERM or SIGINT, how long open connections get to finish before the server exits.
+    /// Defaults to 25
+    #[clap(long, env)]
+    pub shutdown_timeout_secs: Option<u64>,
+}
+
+/// Settings of a single listener.
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ListenerSettings {
+    /// The address to listen on. Defaults to 127.0.0.1:9000
+    #[clap(env)]
+    pub bind_addr: Option<String>,
+
+    /// The pre-shared key for encryption/decryption, accepted as the user "default"
+    #[clap(long, env)]
+    pub key: Option<String>,
+
+    /// A file of users allowed to connect, one `name:password` per line
+    #[clap(long, env)]
+    pub users_file: Option<PathBuf>,
+
+    /// How passwords turn into keys: sha256 (the default), argon2id or raw (the password is a
+    /// base64 key)
+    #[clap(long, env)]
+    pub kdf: Option<String>,
+
+    /// The deployment-wide salt for the argon2id KDF
+    #[clap(long, env)]
+    pub kdf_salt: Option<String>,
+
+    /// A PEM certificate chain to serve TLS with, rather than plain TCP
+    #[clap(long, env, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The PEM private key of `--tls-cert`. Both are reloaded on SIGHUP or when they change.
+    #[clap(long, env, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// How long a UDP association may go without any datagram before it's closed, in
+    /// seconds. Defaults to 60
+    #[clap(long, env)]
+    pub udp_idle_timeout_secs: Option<u64>,
+
+    /// How long a client gets to complete the TLS handshake and send its request, in
+    /// seconds. Defaults to 10
+    #[clap(long, env)]
+    pub handshake_timeout_secs: Option<u64>,
+
+    /// How long connecting to upstream may take, TLS included, in seconds. Defaults to 10
+    #[clap(long, env)]
+    pub upstream_connect_timeout_secs: Option<u64>,
+
+    /// How long a tunnel may go without a byte in either direction before it's closed, in
+    /// seconds. Defaults to 300
+    #[clap(long, env)]
+    pub idle_timeout_secs: Option<u64>,
+
+    /// A web server to hand unauthenticated connections to, e.g. 127.0.0.1:8080
+    #[clap(long, env, conflicts_with = "fallback_dir")]
+    pub fallback_backend: Option<String>,
+
+    /// A directory of static files to serve to unauthenticated connections
+    #[clap(long, env)]
+    pub fallback_dir: Option<PathBuf>,
+
+    /// How long to wait for upstream's first bytes to send back with the response, for
+    /// clients that don't ask for a wait of their own. Defaults to 500
+    #[clap(long, env)]
+    pub initial_response_wait_ms: Option<u64>,
+
+    /// The most bytes of upstream's first answer to send back with the response. Defaults to
+    /// 4096
+    #[clap(long, env)]
+    pub initial_response_max_len: Option<usize>,
+
+    /// What requests look like, as a query string that must match the client's URL, e.g.
+    /// `path_prefix=/api&path_encoding=rest&req_header=X-Token&resp_header=X-Trace`
+    #[clap(long, env)]
+    pub camouflage: Option<String>,
+
+    /// How to connect to upstream over TLS, as a query string, e.g.
+    /// `ca=/etc/ssl/internal.pem&native_roots=true&alpn=h2,http/1.1`
+    #[clap(long, env)]
+    pub upstream_tls: Option<String>,
+
+    /// Serve tunnels over HTTP/2, for clients with the `h2` option. Otherwise TLS doesn't
+    /// offer h2 and HTTP/2 connections get the fallback like any other unknown request
+    #[clap(long, env, num_args = 0..=1, default_missing_value = "true")]
+    pub h2: Option<bool>,
+
+    /// Let clients reach loopback, private, link-local and other special addresses
+    #[clap(long, env, num_args = 0..=1, default_missing_value = "true")]
+    pub allow_special_networks: Option<bool>,
+
+    /// Networks clients may reach even if they are special, e.g. 10.1.0.0/16
+    #[clap(long, env, value_delimiter = ',')]
+    pub allow_networks: Option<Vec<IpNet>>,
+
+    /// Networks clients may never reach
+    #[clap(long, env, value_delimiter = ',')]
+    pub deny_networks: Option<Vec<IpNet>>,
+
+    /// The only ports clients may reach, e.g. 80,443,8000-9000. All by default
+    #[clap(long, env, value_delimiter = ',')]
+    pub allow_ports: Option<Vec<PortRange>>,
+
+    /// Ports clients may never reach, e.g. 25 to keep spam from going out
+    #[clap(long, env, value_delimiter = ',')]
+    pub deny_ports: Option<Vec<PortRange>>,
+
+    /// The only domains clients may reach, like example.com or *.example.com. Addresses
+    /// must then be in --allow-networks, and names are always resolved by the server
+    #[clap(long, env, value_delimiter = ',')]
+    pub allow_domains: Option<Vec<String>>,
+
+    /// Domains clients may never reach, like *.internal. Names are then always resolved by
+    /// the server
+    #[clap(long, env, value_delimiter = ',')]
+    pub deny_domains: Option<Vec<String>>,
+}
+
+impl ServerSettings {
+    /// Takes what isn't set here from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            max_clock_skew_secs: self.max_clock_skew_secs.or(fallback.max_clock_skew_secs),
+            replay_cache_size: self.replay_cache_size.or(fallback.replay_cache_size),
+            metrics_addr: self.metrics_addr.or(fallback.metrics_addr),
+            shutdown_timeout_secs: self
+                .shutdown_timeout_secs
+                .or(fallback.shutdown_timeout_secs),
+        }
+    }
+}
+
+impl ListenerSettings {
+    /// Takes what isn't set here from `fallback`. Settings that only make sense together,
+    /// like a certificate and its key, are taken as a whole.
+    pub fn or(self, fallback: Self) -> Self {
+        let (tls_cert, tls_key) = if self.tls_cert.is_some() || self.tls_key.is_some() {
+            (self.tls_cert, self.tls_key)
+        } else {
+            (fallback.tls_cert, fallback.tls_key)
+        };
+
+        let (fallback_backend, fallback_dir) =
+            if self.fallback_backend.is_some() || self.fallback_dir.is_some() {
+                (self.fallback_backend, self.fallback_dir)
+            } else {
+                (fallback.fallback_backend, fallback.fallback_dir)
+            };
+
+        Self {
+            bind_addr: self.bind_addr.or(fallback.bind_addr),
+            key: self.key.or(fallback.key),
+            users_file: self.users_file.or(fallback.users_file),
+            kdf: self.kdf.or(fallback.kdf),
+            kdf_salt: self.kdf_salt.or(fallback.kdf_salt),
+            tls_cert,
+            tls_key,
+            udp_idle_timeout_secs: self
+                .udp_idle_timeout_secs
+                .or(fallback.udp_idle_timeout_secs),
+            handshake_timeout_secs: self
+                .handshake_timeout_secs
+                .or(fallback.handshake_timeout_secs),
+            upstream_connect_timeout_secs: self
+                .upstream_connect_timeout_secs
+                .or(fallback.upstream_connect_timeout_secs),
+            idle_timeout_secs: self.idle_timeout_secs.or(fallback.idle_timeout_secs),
+            fallback_backend,
+            fallback_dir,
+            initial_response_wait_ms: self
+                .initial_response_wait_ms
+                .or(fallback.initial_response_wait_ms),
+            initial_response_max_len: self
+                .initial_response_max_len
+                .or(fallback.initial_response_max_len),
+            camouflage: self.camouflage.or(fallback.camouflage),
+            upstream_tls: self.upstream_tls.or(fallback.upstream_tls),
+            h2: self.h2.or(fallback.h2),
+            allow_special_networks: self
+                .allow_special_networks
+                .or(fallback.allow_special_networks),
+            allow_networks: self.allow_networks.or(fallback.allow_networks),
+            deny_networks: self.deny_networks.or(fallback.deny_networks),
+            allow_ports: self.allow_ports.or(fallback.allow_ports),
+            deny_ports: self.deny_ports.or(fallback.deny_ports),
+            allow_domains: self.allow_domains.or(fallback.allow_domains),
+            deny_domains: self.deny_domains.or(fallback.deny_domains),
+        }
+    }
+
+    pub fn bind_addr(&self) -> &str {
+        self.bind_addr.as_deref().unwrap_or("127.0.0.1:9000")
+    }
+}
+
+/// A server configuration file, in TOML:
+///
+/// ```toml
+/// [server]
+/// metrics_addr = "127.0.0.1:9100"
+///
+/// # Applies to every listener
+/// [defaults]
+/// users_file = "/etc/cpxy/users"
+///
+/// [[listeners]]
+/// bind_addr = "0.0.0.0:443"
+/// tls_cert = "/etc/cpxy/cert.pem"
+/// tls_key = "/etc/cpxy/key.pem"
+/// camouflage = "path_prefix=/api&path_encoding=rest"
+///
+/// [[listeners]]
+/// bind_addr = "0.0.0.0:8080"
+/// key = "another key"
+/// deny_ports = ["25", "6000-7000"]
+/// ```
+///
+/// The keys are named after the command line options, which win over the file when given.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, default)]
+pub struct ConfigFile {
+    pub server: ServerSettings,
+    pub defaults: ListenerSettings,
+    pub listeners: Vec<ListenerSettings>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Error reading config file {path:?}"))?;
+        toml::from_str(&text).with_context(|| format!("Error parsing config file {path:?}"))
+    }
+
+    /// Layers the command line and environment over the file: what's given there wins over
+    /// each listener's own settings, which win over `[defaults]`. Without listeners in the
+    /// file, there's one made of the command line alone.
+    ///
+    /// Several listeners can't share a bind address, so giving one is an error then.
+    pub fn resolve(
+        self,
+        server: ServerSettings,
+        listener: ListenerSettings,
+    ) -> anyhow::Result<(ServerSettings, Vec<ListenerSettings>)> {
+        if let Some(addr) = &listener.bind_addr {
+            ensure!(
+                self.listeners.len() <= 1,
+                "Can't bind all {} listeners of the config file to {addr}",
+                self.listeners.len()
+            );
+        }
+
+        let listeners = if self.listeners.is_empty() {
+            vec![ListenerSettings::default()]
+        } else {
+            self.listeners
+        };
+
+        let listeners = listeners
+            .into_iter()
+            .map(|l| listener.clone().or(l).or(self.defaults.clone()))
+            .collect::<Vec<_>>();
+
+        for l in &listeners {
+            ensure!(
+                l.tls_cert.is_some() == l.tls_key.is_some(),
+                "Listener {} needs both tls_cert and tls_key",
+                l.bind_addr()
+            );
+            ensure!(
+                l.fallback_backend.is_none() || l.fallback_dir.is_none(),
+                "Listener {} can't have both fallback_backend and fallback_dir",
+                l.bind_addr()
+            );
+        }
+
+        Ok((server.or(self.server), listeners))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_resolves() {
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [server]
+            replay_cache_size = 10
+
+            [defaults]
+            key = "shared"
+            deny_ports = [25, "6000-7000"]
+
+            [[listeners]]
+            bind_addr = "0.0.0.0:443"
+            tls_cert = "cert.pem"
+            tls_key = "key.pem"
+
+            [[listeners]]
+            bind_addr = "0.0.0.0:80"
+            key = "own"
+            fallback_dir = "/srv/www"
+            allow_networks = ["10.1.0.0/16"]
+            "#,
+        )
+        .unwrap();
+
+        // As given on the command line or in the environment, e.g. IDLE_TIMEOUT_SECS
+        let (server, listeners) = file
+            .resolve(
+                ServerSettings {
+                    replay_cache_size: Some(20),
+                    ..Default::default()
+                },
+                ListenerSettings {
+                    idle_timeout_secs: Some(30),
+                    fallback_backend: Some("127.0.0.1:8080".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        assert_eq!(server.replay_cache_size, Some(20));
+        assert_eq!(listeners.len(), 2);
+        // A listener's own settings win over `[defaults]`
+        assert_eq!(listeners[0].key.as_deref(), Some("shared"));
+        assert_eq!(listeners[1].key.as_deref(), Some("own"));
+        assert_eq!(listeners[0].tls_key, Some(PathBuf::from("key.pem")));
+        assert_eq!(
+            listeners[0].deny_ports,
+            Some(vec!["25".parse().unwrap(), "6000-7000".parse().unwrap()])
+        );
+        assert_eq!(listeners[1].bind_addr(), "0.0.0.0:80");
+        // The command line and environment win over both
+        assert_eq!(listeners[0].idle_timeout_secs, Some(30));
+        assert_eq!(listeners[1].idle_timeout_secs, Some(30));
+        assert_eq!(
+            listeners[0].fallback_backend.as_deref(),
+            Some("127.0.0.1:8080")
+        );
+        // The command line's fallback replaces a listener's, rather than conflicting
+        assert_eq!(
+            listeners[1].fallback_backend.as_deref(),
+            Some("127.0.0.1:8080")
+        );
+        assert_eq!(listeners[1].fallback_dir, None);
+
+        // A bind address from the environment, as the Docker image sets, moves a single
+        // listener, and can't apply to several
+        let bind_addr = || ListenerSettings {
+            bind_addr: Some("0.0.0.0:3000".to_string()),
+            ..Default::default()
+        };
+        let file: ConfigFile =
+            toml::from_str("[[listeners]]\nbind_addr = \"0.0.0.0:443\"").unwrap();
+        let (_, listeners) = file.resolve(Default::default(), bind_addr()).unwrap();
+        assert_eq!(listeners[0].bind_addr(), "0.0.0.0:3000");
+
+        let file: ConfigFile = toml::from_str(
+            r#"
+            [[listeners]]
+            bind_addr = "0.0.0.0:443"
+            [[listeners]]
+            bind_addr = "0.0.0.0:80"
+            "#,
+        )
+        .unwrap();
+        assert!(file.resolve(Default::default(), bind_addr()).is_err());
+
+        assert!(toml::from_str::<ConfigFile>("[defaults]\nbind_address = \"x\"").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::Context;
+use cpxy_ng::http_stream::HttpStream;
+use cpxy_ng::relay::copy_bidirectional_with_idle_timeout;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// What anyone who isn't a client gets to see, so that probing the server turns up an
+/// ordinary website.
+pub enum Fallback {
+    /// A bare 404 for everything
+    NotFound,
+    /// The connection is handed to the web server at this address, as it came in
+    Backend(String),
+    /// The files in this directory are served
+    Directory(PathBuf),
+}
+
+impl Fallback {
+    /// Serves a connection that didn't carry a valid request. Whatever the server has read
+    /// from it must still be unread. A backend connection is dropped once it has been idle
+    /// for `idle_timeout`.
+    pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut conn: S,
+        idle_timeout: Duration,
+    ) -> anyhow::Result<()> {
+        match self {
+            Fallback::NotFound => conn
+                .write_all(b"HTTP/1.1 404 Not Found\r\n\r\n")
+                .await
+                .context("Error writing 404 response"),
+
+            Fallback::Backend(addr) => {
+                let mut backend = TcpStream::connect(addr.as_str())
+                    .await
+                    .with_context(|| format!("Error connecting to fallback backend {addr}"))?;
+                let _ = copy_bidirectional_with_idle_timeout(&mut conn, &mut backend, idle_timeout)
+                    .await;
+                Ok(())
+            }
+
+            Fallback::Directory(dir) => serve_file(conn, dir).await,
+        }
+    }
+}
+
+async fn serve_file<S: AsyncRead + AsyncWrite + Unpin>(conn: S, dir: &Path) -> anyhow::Result<()> {
+    let ((method, path), mut conn) = match HttpStream::parse_request(conn, |req| {
+        Ok((
+            req.method.unwrap_or_default().to_string(),
+            req.path.unwrap_or("/").to_string(),
+        ))
+    })
+    .await
+    {
+        Ok(v) => v.take_head(),
+        Err((_, mut conn)) => {
+            return conn
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                .await
+                .context("Error writing 400 response");
+        }
+    };
+
+    let file = match resolve_path(dir, &path).await {
+        Some(file) => tokio::fs::read(&file).await.ok().map(|body| (file, body)),
+        None => None,
+    };
+
+    let (status, content_type, body) = match file {
+        Some((file, body)) => ("200 OK", content_type(&file), body),
+        None => (
+            "404 Not Found",
+            "text/html",
+            b"<html><head><title>404 Not Found</title></head><body><h1>Not Found</h1></body></html>"
+                .to_vec(),
+        ),
+    };
+
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    if method != "HEAD" {
+        response.extend_from_slice(&body);
+    }
+
+    conn.write_all(&response)
+        .await
+        .context("Error writing fallback response")
+}
+
+/// Maps a request path to a file in `dir`, refusing anything that would escape it, symlinks
+/// included. Files that don't exist map to nothing.
+async fn resolve_path(dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let path = request_path.split(['?', '#']).next().unwrap_or_default();
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let dir = tokio::fs::canonicalize(dir).await.ok()?;
+    let mut file = tokio::fs::canonicalize(dir.join(relative)).await.ok()?;
+    if path.ends_with('/') || tokio::fs::metadata(&file).await.ok()?.is_dir() {
+        file = tokio::fs::canonicalize(file.join("index.html"))
+            .await
+            .ok()?;
+    }
+    file.starts_with(&dir).then_some(file)
+}
+
+fn content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(|e| e.to_str()) {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_path_stays_in_directory() {
+        let root = std::env::temp_dir().join(format!("fallback-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = root.join("www");
+        std::fs::create_dir_all(dir.join("css")).unwrap();
+        std::fs::create_dir_all(dir.join("blog")).unwrap();
+        std::fs::write(dir.join("css/site.css"), "").unwrap();
+        std::fs::write(dir.join("blog/index.html"), "").unwrap();
+        std::fs::write(root.join("secret"), "").unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), dir.join("secret")).unwrap();
+        std::os::unix::fs::symlink(&root, dir.join("up")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        assert_eq!(
+            resolve_path(&dir, "/css/site.css?v=2").await,
+            Some(dir.join("css/site.css"))
+        );
+        assert_eq!(
+            resolve_path(&dir, "/blog/").await,
+            Some(dir.join("blog/index.html"))
+        );
+        assert_eq!(
+            resolve_path(&dir, "/blog").await,
+            Some(dir.join("blog/index.html"))
+        );
+        assert_eq!(resolve_path(&dir, "/missing.html").await, None);
+        assert_eq!(resolve_path(&dir, "/../etc/passwd").await, None);
+        assert_eq!(resolve_path(&dir, "/a/../../etc/passwd").await, None);
+        assert_eq!(resolve_path(&dir, "/secret").await, None);
+        assert_eq!(resolve_path(&dir, "/up/secret").await, None);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,19 +1,175 @@
+mod config;
+mod fallback;
+mod metrics;
+mod policy;
+mod replay;
 mod server;
+mod tls;
+mod users;
 
-use clap::Parser;
-use cpxy_ng::{Key, key_util::derive_password};
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches};
+use config::{ConfigFile, ListenerSettings, ServerSettings};
+use cpxy_ng::camouflage::Camouflage;
+use cpxy_ng::key_util::Kdf;
+use cpxy_ng::tls_stream::TlsOptions;
 use dotenvy::dotenv;
+use fallback::Fallback;
+use metrics::Metrics;
+use policy::DestinationPolicy;
+use replay::ReplayFilter;
+use server::ServerContext;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tls::TlsAcceptor;
 use tokio::net::TcpListener;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::task::JoinHandle;
+use tokio::time::timeout_at;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use users::{User, Users};
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
 
 #[derive(clap::Parser)]
 struct CliOptions {
-    /// The pre-shared key for encryption/decryption
+    /// A TOML file declaring the listeners and their settings. The other options win over
+    /// it when given, see `ConfigFile::resolve`.
     #[clap(long, env)]
-    key: String,
+    config_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    server: ServerSettings,
+
+    #[clap(flatten)]
+    listener: ListenerSettings,
+}
+
+/// What's shared by the contexts of all listeners.
+struct Shared {
+    replay_filter: Arc<ReplayFilter>,
+    metrics: Arc<Metrics>,
+    tasks: TaskTracker,
+}
+
+fn load_users(settings: &ListenerSettings) -> anyhow::Result<Users> {
+    let kdf = Kdf::new(
+        settings.kdf.as_deref().unwrap_or("sha256"),
+        settings.kdf_salt.as_deref(),
+    )
+    .context("Invalid KDF options")?;
+
+    let mut users = match &settings.users_file {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).context("Error reading users file")?;
+            Users::parse(&text, &kdf).context("Error parsing users file")?
+        }
+        None => vec![],
+    };
+
+    if let Some(key) = &settings.key {
+        users.push(User {
+            name: "default".to_string(),
+            key: kdf.derive(key).context("Error deriving key")?.into(),
+        });
+    }
 
-    /// The address to listen on for the http proxy
-    #[clap(env, default_value = "127.0.0.1:9000")]
-    bind_addr: String,
+    Users::new(users).context("Error loading users, is a key or users file given?")
+}
+
+fn build_context(settings: ListenerSettings, shared: &Shared) -> anyhow::Result<ServerContext> {
+    let users = load_users(&settings)?;
+    let camouflage = Camouflage::from_query_str(settings.camouflage.as_deref().unwrap_or(""))
+        .context("Invalid camouflage options")?;
+    let upstream_tls = TlsOptions::from_query_str(settings.upstream_tls.as_deref().unwrap_or(""))
+        .context("Invalid upstream TLS options")?;
+
+    let fallback = match (settings.fallback_backend, settings.fallback_dir) {
+        (Some(addr), _) => Fallback::Backend(addr),
+        (None, Some(dir)) => Fallback::Directory(dir),
+        (None, None) => Fallback::NotFound,
+    };
+
+    let lowercase = |domains: Option<Vec<String>>| {
+        domains
+            .unwrap_or_default()
+            .iter()
+            .map(|d| d.to_ascii_lowercase())
+            .collect()
+    };
+
+    Ok(ServerContext {
+        users,
+        replay_filter: shared.replay_filter.clone(),
+        udp_idle_timeout: Duration::from_secs(settings.udp_idle_timeout_secs.unwrap_or(60)),
+        handshake_timeout: Duration::from_secs(settings.handshake_timeout_secs.unwrap_or(10)),
+        upstream_connect_timeout: Duration::from_secs(
+            settings.upstream_connect_timeout_secs.unwrap_or(10),
+        ),
+        idle_timeout: Duration::from_secs(settings.idle_timeout_secs.unwrap_or(300)),
+        fallback,
+        camouflage,
+        initial_response_wait: Duration::from_millis(
+            settings.initial_response_wait_ms.unwrap_or(500),
+        ),
+        initial_response_max_len: settings.initial_response_max_len.unwrap_or(4096),
+        upstream_tls,
+        h2: settings.h2.unwrap_or(false),
+        destination_policy: DestinationPolicy {
+            allow_special_networks: settings.allow_special_networks.unwrap_or(false),
+            allowed_networks: settings.allow_networks.unwrap_or_default(),
+            denied_networks: settings.deny_networks.unwrap_or_default(),
+            allowed_ports: settings.allow_ports.unwrap_or_default(),
+            denied_ports: settings.deny_ports.unwrap_or_default(),
+            allowed_domains: lowercase(settings.allow_domains),
+            denied_domains: lowercase(settings.deny_domains),
+        },
+        metrics: shared.metrics.clone(),
+        tasks: shared.tasks.clone(),
+    })
+}
+
+async fn start_listener(
+    settings: ListenerSettings,
+    shared: &Shared,
+    shutdown: CancellationToken,
+) -> anyhow::Result<JoinHandle<()>> {
+    let bind_addr = settings.bind_addr().to_string();
+    let tls = match (&settings.tls_cert, &settings.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = Arc::new(
+                TlsAcceptor::new(cert.clone(), key.clone(), settings.h2.unwrap_or(false))
+                    .with_context(|| format!("Error loading TLS certificate of {bind_addr}"))?,
+            );
+            tokio::spawn(tls.clone().watch());
+            Some(tls)
+        }
+        _ => None,
+    };
+
+    let ctx = build_context(settings, shared)
+        .with_context(|| format!("Error setting up listener {bind_addr}"))?;
+
+    let listener = TcpListener::bind(&bind_addr)
+        .await
+        .with_context(|| format!("Error binding {bind_addr}"))?;
+
+    tracing::info!(
+        tls = tls.is_some(),
+        "Server listening on {}",
+        listener.local_addr()?
+    );
+
+    Ok(tokio::spawn(accept_loop(
+        listener,
+        tls,
+        Arc::new(ctx),
+        shutdown,
+    )))
 }
 
 #[tokio::main]
@@ -21,18 +177,140 @@ async fn main() {
     let _ = dotenv();
     tracing_subscriber::fmt::init();
 
-    let CliOptions { key, bind_addr } = CliOptions::parse();
+    let matches = CliOptions::command().get_matches();
+    let CliOptions {
+        config_file,
+        server,
+        listener,
+    } = CliOptions::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // The Docker image sets BIND_ADDR, which is easy to miss when it gets in the way
+    let invalid = match matches.value_source("bind_addr") {
+        Some(ValueSource::EnvVariable) => {
+            "Invalid configuration (with BIND_ADDR from the environment)"
+        }
+        _ => "Invalid configuration",
+    };
 
-    let listener = TcpListener::bind(bind_addr)
-        .await
-        .expect("Error binding address");
+    let config = match config_file {
+        Some(path) => ConfigFile::load(&path).expect("Error loading config file"),
+        None => ConfigFile::default(),
+    };
+
+    let (server, listeners) = config.resolve(server, listener).expect(invalid);
+
+    let shared = Shared {
+        replay_filter: Arc::new(ReplayFilter::new(
+            server.max_clock_skew_secs.unwrap_or(120),
+            server.replay_cache_size.unwrap_or(100_000),
+        )),
+        metrics: Arc::new(Metrics::default()),
+        tasks: TaskTracker::new(),
+    };
 
-    tracing::info!("Server listening on {}", listener.local_addr().unwrap());
+    if let Some(addr) = server.metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .expect("Error binding metrics address");
+        tracing::info!("Metrics served on {}", listener.local_addr().unwrap());
+        tokio::spawn(metrics::serve(listener, shared.metrics.clone()));
+    }
+
+    let shutdown = CancellationToken::new();
+    let mut accept_loops = vec![];
 
-    let key: Key = derive_password(&key).into();
+    for settings in listeners {
+        let accept_loop = start_listener(settings, &shared, shutdown.clone())
+            .await
+            .expect("Error starting listener");
+        accept_loops.push(accept_loop);
+    }
+
+    shutdown_signal().await;
+    shutdown.cancel();
+    for accept_loop in accept_loops {
+        let _ = accept_loop.await;
+    }
+
+    let shutdown_timeout_secs = server.shutdown_timeout_secs.unwrap_or(25);
+    let tasks = &shared.tasks;
+    tasks.close();
+    tracing::info!(
+        "Stopped accepting connections, waiting up to {shutdown_timeout_secs}s for {} to finish",
+        tasks.len()
+    );
+
+    tokio::select! {
+        _ = tasks.wait() => tracing::info!("All connections finished"),
+        _ = tokio::time::sleep(Duration::from_secs(shutdown_timeout_secs)) => {
+            tracing::warn!("Shutdown deadline reached, dropping {} connections", tasks.len())
+        }
+        _ = shutdown_signal() => {
+            tracing::warn!("Signalled again, dropping {} connections", tasks.len())
+        }
+    }
+}
+
+/// Accepts connections until `shutdown` is cancelled, then drops the listener.
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<Arc<TlsAcceptor>>,
+    ctx: Arc<ServerContext>,
+    shutdown: CancellationToken,
+) {
+    let mut accept_backoff = MIN_ACCEPT_BACKOFF;
 
     loop {
-        let (socket, addr) = listener.accept().await.expect("Error accepting connection");
-        tokio::spawn(server::handle_connection(socket, addr, key));
+        let (socket, addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    // Usually out of file descriptors, which frees up as connections end
+                    tracing::error!(
+                        "Error accepting connection, retrying in {accept_backoff:?}: {e:?}"
+                    );
+                    tokio::time::sleep(accept_backoff).await;
+                    accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            },
+        };
+
+        accept_backoff = MIN_ACCEPT_BACKOFF;
+        // The TLS handshake and the request share the one deadline
+        let deadline = Instant::now() + ctx.handshake_timeout;
+        let ctx = ctx.clone();
+        Metrics::inc(&ctx.metrics.connections_accepted);
+        match tls.clone() {
+            Some(tls) => ctx.tasks.clone().spawn(async move {
+                let accepted = timeout_at(deadline.into(), tls.accept(socket))
+                    .await
+                    .context("Timed out in TLS handshake")
+                    .and_then(|r| r);
+                match accepted {
+                    Ok(socket) => server::handle_connection(socket, addr, deadline, ctx).await,
+                    Err(e) => {
+                        Metrics::inc(&ctx.metrics.tls_handshake_failures);
+                        tracing::debug!(?addr, "{e:?}");
+                        Err(e)
+                    }
+                }
+            }),
+            None => ctx.tasks.spawn(server::handle_connection(
+                socket,
+                addr,
+                deadline,
+                ctx.clone(),
+            )),
+        };
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Error listening for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
     }
 }
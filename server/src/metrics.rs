@@ -0,0 +1,289 @@
+use crate::{MAX_ACCEPT_BACKOFF, MIN_ACCEPT_BACKOFF};
+use anyhow::Context;
+use cpxy_ng::http_stream::HttpStream;
+use cpxy_ng::protocol::{ErrorCode, TunnelMode};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+static ERROR_CODES: [(ErrorCode, &str); 7] = [
+    (ErrorCode::HostNotFound, "host_not_found"),
+    (ErrorCode::ConnectionRefused, "connection_refused"),
+    (ErrorCode::TimedOut, "timed_out"),
+    (ErrorCode::NetworkUnreachable, "network_unreachable"),
+    (ErrorCode::Forbidden, "forbidden"),
+    (ErrorCode::TlsFailure, "tls_failure"),
+    (ErrorCode::Other, "other"),
+];
+
+static TUNNEL_KINDS: [&str; 4] = ["stream", "mux", "mux_stream", "udp"];
+
+// Upper bounds in seconds
+static LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// What a tunnel carries, as counted by the active tunnels gauge.
+#[derive(Debug, Clone, Copy)]
+pub enum TunnelKind {
+    Stream,
+    Mux,
+    /// A stream inside a mux session
+    MuxStream,
+    Udp,
+}
+
+impl From<TunnelMode> for TunnelKind {
+    fn from(mode: TunnelMode) -> Self {
+        match mode {
+            TunnelMode::Stream => Self::Stream,
+            TunnelMode::Mux => Self::Mux,
+            TunnelMode::Udp => Self::Udp,
+        }
+    }
+}
+
+/// The server's counters, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_accepted: AtomicU64,
+    pub tls_handshake_failures: AtomicU64,
+    /// Requests that didn't decrypt or parse, mostly probes
+    pub parse_failures: AtomicU64,
+    /// Requests that parsed but were refused, such as replays
+    pub rejected_requests: AtomicU64,
+    upstream_errors: [AtomicU64; ERROR_CODES.len()],
+    active_tunnels: [AtomicI64; TUNNEL_KINDS.len()],
+    connect_latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    connect_latency_count: AtomicU64,
+    connect_latency_sum_micros: AtomicU64,
+    bytes_from_client: AtomicU64,
+    bytes_to_client: AtomicU64,
+}
+
+/// Counts a tunnel as active until dropped.
+pub struct ActiveTunnel<'a>(&'a AtomicI64);
+
+impl Drop for ActiveTunnel<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upstream_error(&self, code: ErrorCode) {
+        if let Some(i) = ERROR_CODES.iter().position(|(c, _)| *c == code) {
+            Self::inc(&self.upstream_errors[i]);
+        }
+    }
+
+    pub fn tunnel_opened(&self, kind: TunnelKind) -> ActiveTunnel<'_> {
+        let gauge = &self.active_tunnels[kind as usize];
+        gauge.fetch_add(1, Ordering::Relaxed);
+        ActiveTunnel(gauge)
+    }
+
+    /// Records how long connecting to upstream took, TLS included.
+    pub fn upstream_connected(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.connect_latency_buckets) {
+            if secs <= *bound {
+                Self::inc(bucket);
+            }
+        }
+        Self::inc(&self.connect_latency_count);
+        self.connect_latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records what a relay from upstream to the client copied, however it ended.
+    pub fn relayed(&self, (to_client, from_client): (u64, u64)) {
+        self.bytes_to_client.fetch_add(to_client, Ordering::Relaxed);
+        self.bytes_from_client
+            .fetch_add(from_client, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let get = |v: &AtomicU64| v.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            for (suffix, value) in samples {
+                let _ = writeln!(out, "{name}{suffix} {value}");
+            }
+        };
+        let single = |v: &AtomicU64| [(String::new(), get(v).to_string())];
+
+        family(
+            "cpxy_connections_accepted_total",
+            "counter",
+            "Connections accepted by the server",
+            &single(&self.connections_accepted),
+        );
+        family(
+            "cpxy_tls_handshake_failures_total",
+            "counter",
+            "Connections that failed the TLS handshake",
+            &single(&self.tls_handshake_failures),
+        );
+        family(
+            "cpxy_parse_failures_total",
+            "counter",
+            "Requests that didn't decrypt or parse",
+            &single(&self.parse_failures),
+        );
+        family(
+            "cpxy_rejected_requests_total",
+            "counter",
+            "Requests refused after parsing, such as replays",
+            &single(&self.rejected_requests),
+        );
+        family(
+            "cpxy_upstream_errors_total",
+            "counter",
+            "Failures to reach upstream by kind",
+            &ERROR_CODES
+                .iter()
+                .zip(&self.upstream_errors)
+                .map(|((_, kind), v)| (format!("{{kind=\"{kind}\"}}"), get(v).to_string()))
+                .collect::<Vec<_>>(),
+        );
+        family(
+            "cpxy_active_tunnels",
+            "gauge",
+            "Tunnels open right now by kind",
+            &TUNNEL_KINDS
+                .iter()
+                .zip(&self.active_tunnels)
+                .map(|(kind, v)| {
+                    (
+                        format!("{{kind=\"{kind}\"}}"),
+                        v.load(Ordering::Relaxed).to_string(),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        let count = get(&self.connect_latency_count);
+        let mut latency = LATENCY_BUCKETS
+            .iter()
+            .zip(&self.connect_latency_buckets)
+            .map(|(bound, v)| (format!("_bucket{{le=\"{bound}\"}}"), get(v).to_string()))
+            .collect::<Vec<_>>();
+        latency.push(("_bucket{le=\"+Inf\"}".to_string(), count.to_string()));
+        latency.push((
+            "_sum".to_string(),
+            (get(&self.connect_latency_sum_micros) as f64 / 1e6).to_string(),
+        ));
+        latency.push(("_count".to_string(), count.to_string()));
+        family(
+            "cpxy_upstream_connect_seconds",
+            "histogram",
+            "How long connecting to upstream takes, TLS included",
+            &latency,
+        );
+
+        family(
+            "cpxy_relayed_bytes_total",
+            "counter",
+            "Bytes relayed between clients and upstream by direction",
+            &[
+                (
+                    "{direction=\"upload\"}".to_string(),
+                    get(&self.bytes_from_client).to_string(),
+                ),
+                (
+                    "{direction=\"download\"}".to_string(),
+                    get(&self.bytes_to_client).to_string(),
+                ),
+            ],
+        );
+
+        out
+    }
+}
+
+/// Serves `GET /metrics` to scrapers, a 404 to anything else.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    let mut accept_backoff = MIN_ACCEPT_BACKOFF;
+
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                tracing::warn!("Error accepting metrics connection: {e:?}");
+                tokio::time::sleep(accept_backoff).await;
+                accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+
+        accept_backoff = MIN_ACCEPT_BACKOFF;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_scrape(socket, &metrics).await {
+                tracing::debug!("Error serving metrics: {e:?}");
+            }
+        });
+    }
+}
+
+async fn serve_scrape(conn: TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    let (path, mut conn) =
+        HttpStream::parse_request(conn, |req| Ok(req.path.unwrap_or_default().to_string()))
+            .await
+            .map_err(|(e, _)| e)?
+            .take_head();
+
+    let response = if path.split('?').next() == Some("/metrics") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    conn.write_all(response.as_bytes())
+        .await
+        .context("Error writing metrics response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render() {
+        let metrics = Metrics::default();
+        Metrics::inc(&metrics.connections_accepted);
+        metrics.upstream_error(ErrorCode::Forbidden);
+        metrics.upstream_connected(Duration::from_millis(30));
+        metrics.relayed((100, 20));
+        let tunnel = metrics.tunnel_opened(TunnelKind::MuxStream);
+
+        let text = metrics.render();
+        assert!(text.contains("cpxy_connections_accepted_total 1\n"));
+        assert!(text.contains("cpxy_upstream_errors_total{kind=\"forbidden\"} 1\n"));
+        assert!(text.contains("cpxy_active_tunnels{kind=\"mux_stream\"} 1\n"));
+        assert!(text.contains("cpxy_upstream_connect_seconds_bucket{le=\"0.025\"} 0\n"));
+        assert!(text.contains("cpxy_upstream_connect_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(text.contains("cpxy_upstream_connect_seconds_count 1\n"));
+        assert!(text.contains("cpxy_relayed_bytes_total{direction=\"download\"} 100\n"));
+
+        drop(tunnel);
+        assert!(
+            metrics
+                .render()
+                .contains("cpxy_active_tunnels{kind=\"mux_stream\"} 0\n")
+        );
+    }
+}
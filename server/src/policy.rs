@@ -0,0 +1,190 @@
+use anyhow::{Context, ensure};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/// Ranges no client should reach through us unless allowed explicitly: this host, the
+/// networks it sits in (private networks, fly.io's 6PN among them) and link-local
+/// addresses, where cloud metadata endpoints live.
+static SPECIAL_NETWORKS: LazyLock<Vec<IpNet>> = LazyLock::new(|| {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "64:ff9b:1::/48",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|n| n.parse().unwrap())
+    .collect()
+});
+
+/// An inclusive range of ports, written as `25` or `6000-7000`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortRange(u16, u16);
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let start = start.trim().parse().context("Invalid port")?;
+        let end = end.trim().parse().context("Invalid port")?;
+        ensure!(start <= end, "Invalid port range: {s}");
+        Ok(Self(start, end))
+    }
+}
+
+// Plain ports can be given as numbers in the config file
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Port(u16),
+            Range(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Port(port) => Ok(Self(port, port)),
+            Repr::Range(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl PortRange {
+    fn contains(&self, port: u16) -> bool {
+        (self.0..=self.1).contains(&port)
+    }
+}
+
+/// Where clients may go through the server. Explicit denials always win, explicit
+/// allowances win over the built-in denial of special networks.
+#[derive(Debug, Default)]
+pub struct DestinationPolicy {
+    pub allow_special_networks: bool,
+    pub allowed_networks: Vec<IpNet>,
+    pub denied_networks: Vec<IpNet>,
+    /// When not empty, only these ports can be reached
+    pub allowed_ports: Vec<PortRange>,
+    pub denied_ports: Vec<PortRange>,
+    /// When not empty, only these domains can be reached by name. `*.example.com` matches
+    /// the subdomains of example.com, anything else matches exactly.
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+}
+
+fn domain_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host
+            .strip_suffix(parent)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+impl DestinationPolicy {
+    /// Checks what the client asked for, before anything is resolved.
+    pub fn allows_host(&self, host: &str, port: u16) -> bool {
+        if self.denied_ports.iter().any(|r| r.contains(port))
+            || (!self.allowed_ports.is_empty()
+                && !self.allowed_ports.iter().any(|r| r.contains(port)))
+        {
+            return false;
+        }
+
+        // Addresses are checked by `allows_address`, but can't get around a domain allowlist
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.allowed_domains.is_empty()
+                || self
+                    .allowed_networks
+                    .iter()
+                    .any(|n| n.contains(&ip.to_canonical()));
+        }
+
+        let host = host.to_ascii_lowercase();
+        !self.denied_domains.iter().any(|p| domain_matches(p, &host))
+            && (self.allowed_domains.is_empty()
+                || self
+                    .allowed_domains
+                    .iter()
+                    .any(|p| domain_matches(p, &host)))
+    }
+
+    /// Whether addresses the client resolved on its own may be used. With domain rules they
+    /// may not, as nothing ties them to the domain that was checked.
+    pub fn allows_client_addresses(&self) -> bool {
+        self.allowed_domains.is_empty() && self.denied_domains.is_empty()
+    }
+
+    /// Checks an address the host resolved to, right before connecting to it.
+    pub fn allows_address(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.denied_networks.iter().any(|n| n.contains(&ip)) {
+            return false;
+        }
+
+        self.allow_special_networks
+            || self.allowed_networks.iter().any(|n| n.contains(&ip))
+            || !SPECIAL_NETWORKS.iter().any(|n| n.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_policy_works() {
+        let policy = DestinationPolicy {
+            allowed_networks: vec!["10.1.0.0/16".parse().unwrap()],
+            denied_networks: vec!["1.1.1.1/32".parse().unwrap()],
+            denied_ports: vec!["25".parse().unwrap(), "6000-6100".parse().unwrap()],
+            denied_domains: vec!["*.internal".to_string(), "metadata".to_string()],
+            ..Default::default()
+        };
+
+        assert!(policy.allows_address("8.8.8.8".parse().unwrap()));
+        assert!(policy.allows_address("10.1.2.3".parse().unwrap()));
+        assert!(!policy.allows_address("1.1.1.1".parse().unwrap()));
+        assert!(!policy.allows_address("10.2.0.1".parse().unwrap()));
+        assert!(!policy.allows_address("127.0.0.1".parse().unwrap()));
+        assert!(!policy.allows_address("169.254.169.254".parse().unwrap()));
+        assert!(!policy.allows_address("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!policy.allows_address("fdaa::3".parse().unwrap()));
+
+        assert!(policy.allows_host("example.com", 443));
+        assert!(!policy.allows_host("example.com", 25));
+        assert!(!policy.allows_host("example.com", 6050));
+        assert!(!policy.allows_host("App.Internal", 80));
+        assert!(policy.allows_host("internal", 80));
+        assert!(!policy.allows_host("metadata.", 80));
+        assert!(!policy.allows_client_addresses());
+        assert!(DestinationPolicy::default().allows_client_addresses());
+
+        let policy = DestinationPolicy {
+            allowed_ports: vec!["80".parse().unwrap(), "443".parse().unwrap()],
+            allowed_domains: vec!["*.example.com".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.allows_host("www.example.com", 443));
+        assert!(!policy.allows_host("example.com", 443));
+        assert!(!policy.allows_host("www.example.com", 22));
+        assert!(!policy.allows_host("93.184.215.14", 80));
+    }
+}
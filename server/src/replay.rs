@@ -0,0 +1,114 @@
+use anyhow::ensure;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Mutex;
+
+/// Rejects requests whose timestamp is outside the allowed clock skew, and requests whose
+/// nonce has already been seen within that window. Once `capacity` nonces are waiting to
+/// expire, new requests are refused rather than forgetting a nonce that could be replayed.
+pub struct ReplayFilter {
+    max_skew_seconds: u64,
+    capacity: usize,
+    seen: Mutex<SeenNonces>,
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashSet<[u8; 16]>,
+    // Ordered by expiry so that expired entries can be evicted cheaply
+    by_expiry: BTreeSet<(u64, [u8; 16])>,
+}
+
+impl ReplayFilter {
+    pub fn new(max_skew_seconds: u64, capacity: usize) -> Self {
+        Self {
+            max_skew_seconds,
+            capacity,
+            seen: Default::default(),
+        }
+    }
+
+    pub fn check(
+        &self,
+        timestamp_epoch_seconds: u64,
+        nonce: [u8; 16],
+        now: u64,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            timestamp_epoch_seconds.abs_diff(now) <= self.max_skew_seconds,
+            "Request timestamp {timestamp_epoch_seconds} is outside of the allowed clock skew (now = {now})"
+        );
+
+        let mut seen = self.seen.lock().unwrap();
+
+        // Anything expiring before now would fail the timestamp check anyway
+        while let Some(&(expiry, nonce)) = seen.by_expiry.first() {
+            if expiry >= now {
+                break;
+            }
+            seen.by_expiry.pop_first();
+            seen.nonces.remove(&nonce);
+        }
+
+        ensure!(
+            !seen.nonces.contains(&nonce),
+            "Request nonce has been seen before"
+        );
+        ensure!(
+            seen.nonces.len() < self.capacity,
+            "Replay cache is full of unexpired nonces"
+        );
+
+        seen.nonces.insert(nonce);
+        seen.by_expiry
+            .insert((timestamp_epoch_seconds + self.max_skew_seconds, nonce));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_filter_works() {
+        let filter = ReplayFilter::new(30, 2);
+
+        filter
+            .check(100, [1; 16], 100)
+            .expect("First request to pass");
+        assert!(filter.check(100, [1; 16], 100).is_err(), "Replay must fail");
+        assert!(filter.check(50, [2; 16], 100).is_err(), "Too old must fail");
+        assert!(
+            filter.check(150, [2; 16], 100).is_err(),
+            "Too new must fail"
+        );
+
+        filter
+            .check(110, [2; 16], 110)
+            .expect("Second request to pass");
+        // The first nonce has expired, making room for another
+        filter
+            .check(131, [3; 16], 131)
+            .expect("Third request to pass");
+
+        assert!(filter.check(100, [1; 16], 140).is_err());
+        assert!(filter.check(131, [3; 16], 140).is_err());
+    }
+
+    #[test]
+    fn full_replay_filter_fails_closed() {
+        let filter = ReplayFilter::new(30, 2);
+        filter.check(100, [1; 16], 100).unwrap();
+        filter.check(100, [2; 16], 100).unwrap();
+
+        // A flood of fresh nonces can't push out one that's still in the window
+        assert!(filter.check(100, [3; 16], 100).is_err());
+        assert!(filter.check(100, [1; 16], 110).is_err());
+        assert!(filter.check(100, [2; 16], 110).is_err());
+
+        // Until they expire
+        filter.check(131, [3; 16], 131).unwrap();
+        filter.check(131, [4; 16], 131).unwrap();
+    }
+}
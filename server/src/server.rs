@@ -1,67 +1,388 @@
-use anyhow::Context;
+use crate::fallback::Fallback;
+use crate::metrics::{Metrics, TunnelKind};
+use crate::policy::DestinationPolicy;
+use crate::replay::ReplayFilter;
+use crate::users::Users;
+use anyhow::{Context, ensure, format_err};
+use cpxy_ng::camouflage::Camouflage;
+use cpxy_ng::either_stream::EitherStream;
 use cpxy_ng::encrypt_stream::CipherStream;
+use cpxy_ng::h2::{self, H2Session, IncomingRequest};
+use cpxy_ng::handshake::EphemeralKey;
+use cpxy_ng::hpack::Headers;
+use cpxy_ng::mux::{IncomingStream, MuxSession};
+use cpxy_ng::padding_stream::PaddedStream;
+use cpxy_ng::protocol::{ErrorCode, TunnelMode, random_padding};
+use cpxy_ng::relay::copy_bidirectional_with_idle_timeout;
 use cpxy_ng::time_util::now_epoch_seconds;
-use cpxy_ng::tls_stream::connect_tls;
+use cpxy_ng::tls_stream::{TlsOptions, connect_tls};
+use cpxy_ng::udp::{UdpTunnel, relay_to_socket};
+use cpxy_ng::websocket_stream::{Role, WebSocketStream};
 use cpxy_ng::{Key, http_protocol, protocol};
-use std::net::SocketAddr;
-use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::timeout;
-use tracing::instrument;
-
-#[instrument(ret, skip(conn, key), level = "info")]
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, timeout, timeout_at};
+use tokio_util::task::TaskTracker;
+use tracing::{Instrument, Span, instrument};
+
+pub struct ServerContext {
+    pub users: Users,
+    pub replay_filter: Arc<ReplayFilter>,
+    pub udp_idle_timeout: Duration,
+    /// How long a client gets to complete the TLS handshake and send a valid request
+    pub handshake_timeout: Duration,
+    pub upstream_connect_timeout: Duration,
+    /// How long a tunnel may go without traffic
+    pub idle_timeout: Duration,
+    pub fallback: Fallback,
+    pub camouflage: Camouflage,
+    /// How long to wait for upstream's first bytes, when the request leaves it to us
+    pub initial_response_wait: Duration,
+    pub initial_response_max_len: usize,
+    /// How TLS connections to upstream are made when clients ask for them
+    pub upstream_tls: TlsOptions,
+    /// Whether to serve tunnels over HTTP/2
+    pub h2: bool,
+    pub destination_policy: DestinationPolicy,
+    pub metrics: Arc<Metrics>,
+    /// Everything serving clients, so shutting down can wait for it
+    pub tasks: TaskTracker,
+}
+
+/// The longest a client can ask us to wait for upstream's first bytes
+const MAX_INITIAL_RESPONSE_WAIT: Duration = Duration::from_secs(5);
+
+impl ServerContext {
+    /// A request that leaves the wait to us and has nothing to send gets its response right
+    /// away, as whatever upstream says first follows the response anyway.
+    fn initial_response_wait(
+        &self,
+        requested_ms: Option<u32>,
+        initial_plaintext: &[u8],
+    ) -> Duration {
+        match requested_ms {
+            Some(ms) => Duration::from_millis(ms.into()).min(MAX_INITIAL_RESPONSE_WAIT),
+            None if initial_plaintext.is_empty() => Duration::ZERO,
+            None => self.initial_response_wait,
+        }
+    }
+}
+
+/// Serves a client connection, which has until `deadline` to send a valid request. Slow
+/// clients get the same deadline for the whole request, TLS handshake included, however
+/// they trickle it in.
+#[instrument(ret, skip(conn, ctx), fields(user), level = "info")]
 pub async fn handle_connection(
-    conn: impl AsyncRead + AsyncWrite + Unpin,
+    conn: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
     _from_addr: SocketAddr,
-    key: Key,
+    deadline: Instant,
+    ctx: Arc<ServerContext>,
 ) -> anyhow::Result<()> {
-    let (req, mut conn) = match http_protocol::Request::parse(conn, &key).await {
+    let (is_h2, conn) = timeout_at(deadline.into(), sniff_h2(conn))
+        .await
+        .context("Timed out reading request")??;
+    if is_h2 && ctx.h2 {
+        let incoming = timeout_at(deadline.into(), H2Session::server(conn))
+            .await
+            .context("Timed out in HTTP/2 handshake")??;
+        let accepted = Arc::new(AtomicBool::new(false));
+        return serve_session(incoming, deadline, &accepted, &ctx, |request| {
+            handle_h2_request(request, accepted.clone(), ctx.clone())
+        })
+        .await;
+    }
+
+    let mut user = None;
+    let find_key = |hint: &_| {
+        user = ctx.users.find(hint);
+        user.map(|u| u.key)
+    };
+
+    let parsed = timeout_at(
+        deadline.into(),
+        http_protocol::Request::parse(conn, &ctx.camouflage, find_key),
+    )
+    .await
+    .context("Timed out reading request")?;
+    let (mut req, conn) = match parsed {
         Ok(v) => v.take_head(),
-        Err((err, mut conn)) => {
-            let _ = conn
-                .write_all("HTTP/1.1 404 Not Found\r\n\r\n".as_bytes())
-                .await;
+        Err((err, conn)) => {
+            Metrics::inc(&ctx.metrics.parse_failures);
+            return Err(serve_fallback(conn, err, &ctx).await);
+        }
+    };
+
+    let user = user.context("Request parsed without a user")?;
+    Span::current().record("user", user.name.as_str());
+
+    let server_ephemeral_key = match accept_request(&mut req.request, &ctx) {
+        Ok(v) => v,
+        Err(err) => {
+            Metrics::inc(&ctx.metrics.rejected_requests);
+            return Err(serve_fallback(conn.rewind(), err, &ctx).await);
+        }
+    };
+
+    let responder = Http1Responder {
+        conn,
+        websocket_key: req.websocket_key,
+        key: user.key,
+        camouflage: &ctx.camouflage,
+    };
+    serve_request(req.request, server_ephemeral_key, responder, &ctx).await
+}
+
+#[instrument(ret, skip_all, fields(user), level = "info")]
+/// Serves a request on an HTTP/2 connection, setting `accepted` if it's a valid one.
+async fn handle_h2_request(
+    request: IncomingRequest,
+    accepted: Arc<AtomicBool>,
+    ctx: Arc<ServerContext>,
+) -> anyhow::Result<()> {
+    let mut user = None;
+    let find_key = |hint: &_| {
+        user = ctx.users.find(hint);
+        user.map(|u| u.key)
+    };
+
+    let parsed =
+        http_protocol::Request::from_h2_headers(&request.headers, &ctx.camouflage, find_key);
+    let (mut req, user) = match (parsed, user) {
+        (Ok(req), Some(user)) => (req, user),
+        (parsed, _) => {
+            Metrics::inc(&ctx.metrics.parse_failures);
+            let _ = request.respond_empty(&not_found_headers()).await;
+            return Err(parsed
+                .err()
+                .unwrap_or_else(|| format_err!("Request parsed without a user")));
+        }
+    };
+    Span::current().record("user", user.name.as_str());
+
+    let server_ephemeral_key = match accept_request(&mut req.request, &ctx) {
+        Ok(v) => v,
+        Err(err) => {
+            Metrics::inc(&ctx.metrics.rejected_requests);
+            let _ = request.respond_empty(&not_found_headers()).await;
             return Err(err);
         }
     };
+    accepted.store(true, Ordering::Relaxed);
 
-    let upstream = async {
-        let upstream = TcpStream::connect((req.request.host.as_str(), req.request.port))
-            .await
-            .context("Error connecting to upstream")?;
+    let responder = H2Responder {
+        request,
+        key: user.key,
+        camouflage: &ctx.camouflage,
+    };
+    serve_request(req.request, server_ephemeral_key, responder, &ctx).await
+}
 
-        upstream
-            .set_nodelay(true)
-            .context("Error setting nodelay")?;
+/// Serves everything the client of a session opens, until the client goes away or the
+/// session idles, with nothing open and nothing new for `idle_timeout`. Until `accepted` is
+/// set, the session is closed at `deadline` whatever it's doing.
+async fn serve_session<T, F>(
+    mut incoming: mpsc::Receiver<T>,
+    mut deadline: Instant,
+    accepted: &AtomicBool,
+    ctx: &ServerContext,
+    mut serve: impl FnMut(T) -> F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let open = TaskTracker::new();
+    let mut last_opened = Instant::now();
+
+    loop {
+        tokio::select! {
+            item = incoming.recv() => match item {
+                Some(item) => {
+                    last_opened = Instant::now();
+                    ctx.tasks.spawn(open.track_future(serve(item)).in_current_span());
+                }
+                None => return Ok(()),
+            },
+
+            _ = sleep_until(deadline.into()) => {
+                ensure!(
+                    accepted.load(Ordering::Relaxed),
+                    "Timed out waiting for a valid request"
+                );
+                if !open.is_empty() {
+                    deadline = Instant::now() + ctx.idle_timeout;
+                } else if last_opened.elapsed() < ctx.idle_timeout {
+                    deadline = last_opened + ctx.idle_timeout;
+                } else {
+                    // Dropping the receiver closes the session
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
 
-        let mut upstream =
-            connect_tls(req.request.host.as_str(), req.request.tls, upstream).await?;
+/// Checks a request against replays and sets up its stream ciphers, giving the server's
+/// ephemeral key if the client asked for a key exchange.
+fn accept_request(
+    request: &mut protocol::Request,
+    ctx: &ServerContext,
+) -> anyhow::Result<Option<[u8; 32]>> {
+    // Replays get the same treatment as garbage, so they reveal nothing more
+    ctx.replay_filter.check(
+        request.timestamp_epoch_seconds,
+        request.nonce,
+        now_epoch_seconds(),
+    )?;
 
-        tracing::debug!(
-            "Writing initial plaintext: {}",
-            std::str::from_utf8(&req.request.initial_plaintext).unwrap_or("<non-utf8>")
-        );
+    let server_ephemeral_key = match request.client_ephemeral_key {
+        Some(client_public) => {
+            let ephemeral_key = EphemeralKey::random();
+            let server_public = ephemeral_key.public_key();
+            let (client_send_cipher, server_send_cipher) = ephemeral_key.server_derive(
+                &client_public,
+                &request.client_send_cipher,
+                &request.server_send_cipher,
+            )?;
+            request.client_send_cipher = client_send_cipher;
+            request.server_send_cipher = server_send_cipher;
+            Some(server_public)
+        }
+        None => None,
+    };
 
-        upstream
-            .write_all(&req.request.initial_plaintext)
-            .await
-            .context("Error writing initial plaintext")?;
+    tracing::info!(
+        mode = ?request.mode,
+        host = request.host,
+        port = request.port,
+        "Request accepted"
+    );
 
-        // Try to read some initial data if sent
-        let mut initial_response = vec![0u8; 4096];
+    Ok(server_ephemeral_key)
+}
 
-        match timeout(
-            Duration::from_millis(500),
-            upstream.read(&mut initial_response),
-        )
+/// Sends the response to an accepted request, however the request came in.
+trait Responder {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn respond(
+        self,
+        response: protocol::Response,
+    ) -> impl Future<Output = anyhow::Result<Self::Stream>> + Send;
+}
+
+struct Http1Responder<'a, S> {
+    conn: S,
+    websocket_key: Vec<u8>,
+    key: Key,
+    camouflage: &'a Camouflage,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Responder for Http1Responder<'_, S> {
+    type Stream = S;
+
+    async fn respond(mut self, response: protocol::Response) -> anyhow::Result<S> {
+        http_protocol::Response {
+            response,
+            websocket_key: self.websocket_key,
+        }
+        .send_over_http(&mut self.conn, &self.key, self.camouflage)
         .await
-        {
-            Ok(Ok(n)) => initial_response.truncate(n),
-            Ok(Err(e)) => return Err(e).context("Error reading initial response from upstream"),
-            Err(_) => initial_response.clear(), // Timeout
+        .context("Error sending response")?;
+        Ok(self.conn)
+    }
+}
+
+struct H2Responder<'a> {
+    request: IncomingRequest,
+    key: Key,
+    camouflage: &'a Camouflage,
+}
+
+impl Responder for H2Responder<'_> {
+    type Stream = DuplexStream;
+
+    async fn respond(self, response: protocol::Response) -> anyhow::Result<DuplexStream> {
+        let headers = http_protocol::Response {
+            response,
+            websocket_key: vec![],
         }
+        .to_h2_headers(&self.key, self.camouflage)?;
+
+        self.request
+            .respond(&headers)
+            .await
+            .context("Error sending response")
+    }
+}
+
+async fn serve_request(
+    request: protocol::Request,
+    server_ephemeral_key: Option<[u8; 32]>,
+    responder: impl Responder,
+    ctx: &Arc<ServerContext>,
+) -> anyhow::Result<()> {
+    let _tunnel = ctx.metrics.tunnel_opened(request.mode.into());
+    if matches!(request.mode, TunnelMode::Mux | TunnelMode::Udp) {
+        let conn = responder
+            .respond(protocol::Response::Success {
+                initial_response: vec![],
+                timestamp_epoch_seconds: now_epoch_seconds(),
+                server_ephemeral_key,
+                padding: random_padding(request.max_padding_len),
+            })
+            .await?;
+
+        let conn = upgraded_stream(conn, &request);
+
+        if request.mode == TunnelMode::Udp {
+            return relay_to_socket(
+                UdpTunnel::over_stream(conn),
+                ctx.udp_idle_timeout,
+                |host, addr| {
+                    ctx.destination_policy.allows_host(host, addr.port())
+                        && ctx.destination_policy.allows_address(addr.ip())
+                },
+            )
+            .await;
+        }
+
+        // The session's request was valid already, so it's only held to the idle timeout
+        return serve_session(
+            MuxSession::server(conn),
+            Instant::now() + ctx.idle_timeout,
+            &AtomicBool::new(true),
+            ctx,
+            |stream| handle_mux_stream(stream, ctx.clone()),
+        )
+        .await;
+    }
+
+    let upstream = async {
+        let started = Instant::now();
+        let mut upstream = connect_upstream(
+            &request.host,
+            request.port,
+            &request.addresses,
+            request.trust_addresses,
+            request.tls,
+            ctx,
+        )
+        .await?;
+        ctx.metrics.upstream_connected(started.elapsed());
 
+        let initial_response = exchange_initial_data(
+            &mut upstream,
+            &request.initial_plaintext,
+            ctx.initial_response_wait(request.initial_response_wait_ms, &request.initial_plaintext),
+            ctx.initial_response_max_len,
+        )
+        .await?;
         anyhow::Ok((upstream, initial_response))
     };
 
@@ -69,36 +390,320 @@ pub async fn handle_connection(
         Ok((mut upstream, initial_response)) => {
             tracing::debug!("Upstream connection established");
 
-            http_protocol::Response {
-                response: protocol::Response::Success {
+            let conn = responder
+                .respond(protocol::Response::Success {
                     initial_response,
                     timestamp_epoch_seconds: now_epoch_seconds(),
-                },
-                websocket_key: req.websocket_key,
-            }
-            .send_over_http(&mut conn, &key)
-            .await
-            .context("Error sending response")?;
+                    server_ephemeral_key,
+                    padding: random_padding(request.max_padding_len),
+                })
+                .await?;
 
-            let mut conn = CipherStream::new(
-                conn,
-                &req.request.server_send_cipher,
-                &req.request.client_send_cipher,
-            );
+            let mut conn = upgraded_stream(conn, &request);
 
-            let _ = tokio::io::copy_bidirectional(&mut upstream, &mut conn).await;
+            let (relayed, _) =
+                copy_bidirectional_with_idle_timeout(&mut upstream, &mut conn, ctx.idle_timeout)
+                    .await;
+            ctx.metrics.relayed(relayed);
             anyhow::Ok(())
         }
 
-        Err(e) => http_protocol::Response {
-            response: protocol::Response::Error {
-                msg: format!("{e:?}"),
-                timestamp_epoch_seconds: now_epoch_seconds(),
+        Err(e) => {
+            tracing::warn!("Error connecting to upstream: {e:?}");
+            ctx.metrics.upstream_error(ErrorCode::of(&e));
+            responder
+                .respond(protocol::Response::Error {
+                    code: ErrorCode::of(&e),
+                    timestamp_epoch_seconds: now_epoch_seconds(),
+                    padding: random_padding(request.max_padding_len),
+                })
+                .await
+                .map(|_| ())
+        }
+    }
+}
+
+/// Reads just enough of a connection to tell whether it's HTTP/2, handing back a stream
+/// that still starts with what was read.
+async fn sniff_h2<S: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: S,
+) -> anyhow::Result<(bool, impl AsyncRead + AsyncWrite + Unpin + use<S>)> {
+    let mut buf = vec![];
+    let mut tmp = [0u8; h2::PREFACE.len()];
+    while buf.len() < h2::PREFACE.len() && h2::PREFACE.starts_with(&buf) {
+        let n = conn
+            .read(&mut tmp[..h2::PREFACE.len() - buf.len()])
+            .await
+            .context("Error reading from client")?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&tmp[..n]);
+    }
+
+    let is_h2 = buf == h2::PREFACE;
+    let (r, w) = tokio::io::split(conn);
+    Ok((is_h2, tokio::io::join(Cursor::new(buf).chain(r), w)))
+}
+
+fn not_found_headers() -> Headers {
+    vec![(":status".to_string(), "404".to_string())]
+}
+
+/// Wraps the connection after the upgrade response has been sent, as the request asks for.
+fn upgraded_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: S,
+    request: &protocol::Request,
+) -> PaddedStream<CipherStream<EitherStream<WebSocketStream<S>, S>>> {
+    let conn = if request.websocket_framing {
+        EitherStream::Left(WebSocketStream::new(conn, Role::Server))
+    } else {
+        EitherStream::Right(conn)
+    };
+
+    let conn = CipherStream::new(
+        conn,
+        &request.server_send_cipher,
+        &request.client_send_cipher,
+    );
+
+    PaddedStream::new(conn, request.padded_packets, request.max_padding_len)
+}
+
+#[instrument(
+    ret,
+    skip_all,
+    fields(host = stream.request.host, port = stream.request.port),
+    level = "info"
+)]
+async fn handle_mux_stream(stream: IncomingStream, ctx: Arc<ServerContext>) -> anyhow::Result<()> {
+    let _tunnel = ctx.metrics.tunnel_opened(TunnelKind::MuxStream);
+    let request = &stream.request;
+    let upstream = async {
+        let started = Instant::now();
+        let mut upstream = connect_upstream(
+            &request.host,
+            request.port,
+            &request.addresses,
+            request.trust_addresses,
+            request.tls,
+            &ctx,
+        )
+        .await?;
+        ctx.metrics.upstream_connected(started.elapsed());
+
+        let initial_response = exchange_initial_data(
+            &mut upstream,
+            &request.initial_plaintext,
+            ctx.initial_response_wait(request.initial_response_wait_ms, &request.initial_plaintext),
+            ctx.initial_response_max_len,
+        )
+        .await?;
+        anyhow::Ok((upstream, initial_response))
+    };
+
+    match upstream.await {
+        Ok((mut upstream, initial_response)) => {
+            let mut conn = stream.accept(initial_response).await?;
+            let (relayed, _) =
+                copy_bidirectional_with_idle_timeout(&mut upstream, &mut conn, ctx.idle_timeout)
+                    .await;
+            ctx.metrics.relayed(relayed);
+            Ok(())
+        }
+
+        Err(e) => {
+            tracing::warn!("Error connecting to upstream: {e:?}");
+            ctx.metrics.upstream_error(ErrorCode::of(&e));
+            stream.reject(ErrorCode::of(&e)).await
+        }
+    }
+}
+
+async fn connect_upstream(
+    host: &str,
+    port: u16,
+    addresses: &[IpAddr],
+    trust_addresses: bool,
+    tls: bool,
+    ctx: &ServerContext,
+) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin + use<>> {
+    let connect = async {
+        let upstream = connect_tcp(
+            host,
+            port,
+            addresses,
+            trust_addresses,
+            &ctx.destination_policy,
+            ctx.upstream_connect_timeout,
+        )
+        .await?;
+
+        upstream
+            .set_nodelay(true)
+            .context("Error setting nodelay")?;
+
+        connect_tls(host, tls, upstream, &ctx.upstream_tls)
+            .await
+            .context(ErrorCode::TlsFailure)
+    };
+
+    timeout(ctx.upstream_connect_timeout, connect)
+        .await
+        .map_err(|_| format_err!("Timed out connecting to {host}:{port}"))
+        .context(ErrorCode::TimedOut)?
+}
+
+/// Sends the client's initial data upstream, then waits up to `wait` for the start of the
+/// answer to send back with the response. Anything later is relayed after the response.
+async fn exchange_initial_data(
+    upstream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    initial_plaintext: &[u8],
+    wait: Duration,
+    max_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    tracing::debug!(
+        "Writing initial plaintext: {}",
+        std::str::from_utf8(initial_plaintext).unwrap_or("<non-utf8>")
+    );
+
+    upstream
+        .write_all(initial_plaintext)
+        .await
+        .context("Error writing initial plaintext")?;
+
+    if wait.is_zero() || max_len == 0 {
+        return Ok(vec![]);
+    }
+
+    // Try to read some initial data if sent
+    let mut initial_response = vec![0u8; max_len];
+
+    match timeout(wait, upstream.read(&mut initial_response)).await {
+        Ok(Ok(n)) => initial_response.truncate(n),
+        Ok(Err(e)) => return Err(e).context("Error reading initial response from upstream"),
+        Err(_) => initial_response.clear(), // Timeout
+    }
+
+    Ok(initial_response)
+}
+
+/// Hands a rejected connection to the fallback, so it looks like any other website.
+async fn serve_fallback(
+    conn: impl AsyncRead + AsyncWrite + Unpin,
+    err: anyhow::Error,
+    ctx: &ServerContext,
+) -> anyhow::Error {
+    if let Err(e) = ctx.fallback.serve(conn, ctx.idle_timeout).await {
+        tracing::debug!("Error serving fallback: {e:?}");
+    }
+    err
+}
+
+/// Connects to the addresses the client resolved, or to what `host` resolves to here. Only
+/// the addresses the policy allows are tried.
+async fn connect_tcp(
+    host: &str,
+    port: u16,
+    mut addresses: &[IpAddr],
+    trust_addresses: bool,
+    policy: &DestinationPolicy,
+    connect_timeout: Duration,
+) -> anyhow::Result<TcpStream> {
+    if !policy.allows_host(host, port) {
+        return Err(format_err!(
+            "{host}:{port} is not allowed by the destination policy"
+        ))
+        .context(ErrorCode::Forbidden);
+    }
+
+    if !policy.allows_client_addresses() {
+        addresses = &[];
+    }
+
+    let allowed = |addrs: &mut Vec<SocketAddr>| -> anyhow::Result<()> {
+        addrs.retain(|addr| policy.allows_address(addr.ip()));
+        if addrs.is_empty() {
+            return Err(format_err!(
+                "No address of {host} is allowed by the destination policy"
+            ))
+            .context(ErrorCode::Forbidden);
+        }
+        Ok(())
+    };
+
+    if !addresses.is_empty() {
+        let mut addrs: Vec<SocketAddr> = addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect();
+
+        // Unless they're all there is, the given addresses get half the time, so a
+        // blackholed one leaves time to resolve the host and try again
+        let wait = if trust_addresses {
+            connect_timeout
+        } else {
+            connect_timeout / 2
+        };
+        let connected = match allowed(&mut addrs) {
+            Ok(()) => match timeout(wait, TcpStream::connect(addrs.as_slice())).await {
+                Ok(r) => r.context("Error connecting to upstream"),
+                Err(_) => Err(format_err!("Timed out connecting to the given addresses"))
+                    .context(ErrorCode::TimedOut),
             },
-            websocket_key: req.websocket_key,
+            Err(e) => Err(e),
+        };
+
+        match connected {
+            Ok(upstream) => return Ok(upstream),
+            Err(e) if trust_addresses => return Err(e),
+            Err(e) => {
+                tracing::debug!(
+                    ?e,
+                    "Error connecting to the given addresses, resolving {host}"
+                )
+            }
         }
-        .send_over_http(&mut conn, &key)
+    }
+
+    let mut addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .context(ErrorCode::HostNotFound)?
+        .collect();
+    ensure!(!addrs.is_empty(), ErrorCode::HostNotFound);
+    allowed(&mut addrs)?;
+
+    TcpStream::connect(addrs.as_slice())
+        .await
+        .context("Error connecting to upstream")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_tcp_resolves_checked_domains() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let policy = DestinationPolicy {
+            allowed_networks: vec!["127.0.0.0/8".parse().unwrap()],
+            allowed_domains: vec!["localhost".to_string()],
+            ..Default::default()
+        };
+
+        // An allowed name can't smuggle in addresses of the client's choosing
+        let upstream = connect_tcp(
+            "localhost",
+            port,
+            &["192.0.2.1".parse().unwrap()],
+            true,
+            &policy,
+            Duration::from_secs(5),
+        )
         .await
-        .context("Error sending response"),
+        .unwrap();
+        assert_eq!(upstream.peer_addr().unwrap().port(), port);
     }
 }
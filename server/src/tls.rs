@@ -0,0 +1,105 @@
+use anyhow::{Context, format_err};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::server::TlsStream;
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Terminates TLS with a certificate that can be swapped while the server runs. Connections
+/// keep the certificate they were accepted with.
+pub struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    h2: bool,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+fn load_config(cert_path: &Path, key_path: &Path, h2: bool) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format_err!("Error reading certificates from {cert_path:?}: {e}"))?;
+
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format_err!("Error reading private key from {key_path:?}: {e}"))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+
+    // Only offer h2 when HTTP/2 tunnels are served, as anything else speaking it can't be
+    // handed to the fallback
+    config.alpn_protocols = match h2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+    Ok(Arc::new(config))
+}
+
+fn modified_times(paths: [&Path; 2]) -> Option<[SystemTime; 2]> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some([modified(paths[0])?, modified(paths[1])?])
+}
+
+impl TlsAcceptor {
+    /// With `h2`, ALPN offers HTTP/2 as well as HTTP/1.1.
+    pub fn new(cert_path: PathBuf, key_path: PathBuf, h2: bool) -> anyhow::Result<Self> {
+        let config = load_config(&cert_path, &key_path, h2)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            h2,
+            config: RwLock::new(config),
+        })
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> anyhow::Result<TlsStream<TcpStream>> {
+        let config = self.config.read().unwrap().clone();
+        tokio_rustls::TlsAcceptor::from(config)
+            .accept(stream)
+            .await
+            .context("Error accepting TLS connection")
+    }
+
+    fn reload(&self) -> anyhow::Result<()> {
+        let config = load_config(&self.cert_path, &self.key_path, self.h2)?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+
+    /// Reloads the certificate on SIGHUP, or when the files change.
+    pub async fn watch(self: Arc<Self>) {
+        let mut hangup = signal(SignalKind::hangup()).expect("Error listening for SIGHUP");
+        let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+        let paths = [self.cert_path.as_path(), self.key_path.as_path()];
+        let mut last_modified = modified_times(paths);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {}
+                _ = interval.tick() => {
+                    if modified_times(paths) == last_modified {
+                        continue;
+                    }
+                }
+            }
+
+            // A failed reload is tried again on the next check, the files may be half written
+            match self.reload() {
+                Ok(()) => {
+                    last_modified = modified_times(paths);
+                    tracing::info!("TLS certificate reloaded");
+                }
+                Err(e) => {
+                    tracing::error!("Error reloading TLS certificate, keeping the old one: {e:?}")
+                }
+            }
+        }
+    }
+}
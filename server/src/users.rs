@@ -0,0 +1,88 @@
+use anyhow::{Context, bail, ensure};
+use cpxy_ng::Key;
+use cpxy_ng::key_util::{Kdf, KeyHint, key_hint_matches};
+
+pub struct User {
+    pub name: String,
+    pub key: Key,
+}
+
+/// The users allowed to connect, told apart by the key hint their requests carry.
+pub struct Users {
+    users: Vec<User>,
+}
+
+impl Users {
+    pub fn new(users: impl IntoIterator<Item = User>) -> anyhow::Result<Self> {
+        let mut all: Vec<User> = vec![];
+        for user in users {
+            ensure!(
+                !all.iter().any(|u| u.name == user.name),
+                "Duplicate user: {}",
+                user.name
+            );
+
+            if let Some(existing) = all.iter().find(|u| u.key == user.key) {
+                bail!("User {} shares a key with another user", existing.name);
+            }
+            all.push(user);
+        }
+
+        ensure!(!all.is_empty(), "At least one user is required");
+        Ok(Self { users: all })
+    }
+
+    /// Parses a users file: one `name:password` per line, blank lines and `#` comments ignored.
+    pub fn parse(text: &str, kdf: &Kdf) -> anyhow::Result<Vec<User>> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (name, password) = line
+                    .split_once(':')
+                    .with_context(|| format!("Expected name:password but got {line}"))?;
+                let name = name.trim();
+                ensure!(!name.is_empty(), "User name can't be empty");
+
+                Ok(User {
+                    name: name.to_string(),
+                    key: kdf
+                        .derive(password.trim())
+                        .with_context(|| format!("Error deriving key for {name}"))?
+                        .into(),
+                })
+            })
+            .collect()
+    }
+
+    /// Finds whose key made `hint`. Hints are salted, so every user's key is tried.
+    pub fn find(&self, hint: &KeyHint) -> Option<&User> {
+        self.users.iter().find(|u| key_hint_matches(&u.key, hint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpxy_ng::key_util::{derive_password, key_hint};
+
+    #[test]
+    fn users_work() {
+        let users =
+            Users::parse("# The team\nalice: secret1\n\nbob:secret2\n", &Kdf::Sha256).unwrap();
+        let users = Users::new(users).unwrap();
+
+        let alice_key: Key = derive_password("secret1").into();
+        let alice = users.find(&key_hint(&alice_key)).unwrap();
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.key, alice_key);
+
+        let unknown_key: Key = derive_password("secret3").into();
+        assert!(users.find(&key_hint(&unknown_key)).is_none());
+
+        assert!(Users::new(Users::parse("alice:a\nalice:b", &Kdf::Sha256).unwrap()).is_err());
+        assert!(Users::new(Users::parse("alice:a\nbob:a", &Kdf::Sha256).unwrap()).is_err());
+        assert!(Users::parse("alice", &Kdf::Sha256).is_err());
+        assert!(Users::parse("alice:not-base64", &Kdf::Raw).is_err());
+    }
+}